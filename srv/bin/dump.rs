@@ -0,0 +1,150 @@
+use anyhow::Result;
+use clap::{App, AppSettings, Arg, SubCommand};
+use srv::db::DB;
+use srv::server;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::net::SocketAddr;
+
+fn main() -> Result<()> {
+    let matches = App::new("dump")
+        .about("Manage a dump DB")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .value_name("PATH")
+                .help("The path of the DB")
+                .default_value("~/.dump.db"),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the DB as a JSONL backup")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("The path of the backup (stdout if omitted)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Restore a JSONL backup in a fresh DB")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("The path of the backup")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("List, inspect, repair or discard quarantined records")
+                .arg(
+                    Arg::with_name("action")
+                        .value_name("ACTION")
+                        .possible_values(&["list", "inspect", "repair", "discard"])
+                        .default_value("list"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .value_name("KEY")
+                        .help("The key of the quarantined record (`<tree>/<key>`)")
+                        .required_ifs(&[
+                            ("action", "inspect"),
+                            ("action", "repair"),
+                            ("action", "discard"),
+                        ]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("postprocess")
+                .about("Run the postprocessing pass on all entries if it is pending"),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve the HTTP API (feeds) and run schedules")
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("The address to listen on")
+                        .default_value("127.0.0.1:13371"),
+                ),
+        )
+        .get_matches();
+
+    let db = DB::new(String::from(shellexpand::tilde(
+        matches.value_of("db").unwrap(),
+    )))?;
+
+    match matches.subcommand() {
+        ("export", Some(m)) => {
+            let report = match m.value_of("file") {
+                Some(path) => db.export_jsonl(BufWriter::new(File::create(path)?))?,
+                None => db.export_jsonl(io::stdout().lock())?,
+            };
+            eprintln!(
+                "Exported {} streams and {} entries (schema version {})",
+                report.streams, report.entries, report.schema_version
+            );
+        }
+        ("import", Some(m)) => {
+            let file = File::open(m.value_of("file").unwrap())?;
+            let report = db.import_jsonl(BufReader::new(file))?;
+            eprintln!(
+                "Imported {} streams and {} entries (schema version {})",
+                report.streams, report.entries, report.schema_version
+            );
+        }
+        ("doctor", Some(m)) => {
+            let key = m.value_of("key").unwrap_or("");
+            match m.value_of("action").unwrap() {
+                "inspect" => {
+                    let i = db.inspect_quarantined(key)?;
+                    println!(
+                        "{} ({} bytes): {}",
+                        i.summary.key, i.summary.size, i.summary.error
+                    );
+                    println!("{}", i.hex);
+                    match i.decoded {
+                        Some(d) => println!("{}", d),
+                        None => println!("Unable to decode the record"),
+                    }
+                }
+                "repair" => {
+                    db.repair_quarantined(key)?;
+                    eprintln!("Repaired {}", key);
+                }
+                "discard" => {
+                    db.discard_quarantined(key)?;
+                    eprintln!("Discarded {}", key);
+                }
+                _ => {
+                    for q in db.list_quarantined()? {
+                        println!("{}\t{}\t{}", q.key, q.size, q.error);
+                    }
+                }
+            }
+        }
+        ("postprocess", Some(_)) => {
+            let p = db.postprocess(usize::MAX, &mut |p| {
+                if p.processed % 1000 == 0 {
+                    eprintln!("Postprocessed {}/{} entries", p.processed, p.total);
+                }
+            })?;
+            eprintln!(
+                "Postprocessed {}/{} entries (version {})",
+                p.processed, p.total, p.version
+            );
+        }
+        ("serve", Some(m)) => {
+            tracing_subscriber::fmt::init();
+            db.postprocess(usize::MAX, &mut |_| {})?;
+            let addr: SocketAddr = m.value_of("addr").unwrap().parse()?;
+            tokio::runtime::Runtime::new()?.block_on(server::serve(db, addr));
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
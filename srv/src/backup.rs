@@ -0,0 +1,317 @@
+use crate::db::{DB, INBOX_ID, SCHEMA_VERSION};
+use crate::fields;
+use crate::models::{Entry, Stream};
+use crate::schedules::Schedule;
+use crate::sort;
+use crate::templates::Template;
+use crate::time;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// First line of a JSONL backup, recording the schema version of the records that follow.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Header {
+    pub format: String,
+    pub schema_version: u32,
+    pub created: u64,
+}
+
+/// A line of a JSONL backup. Entries are dumped with their raw id-based meta
+/// (`_stream_id_[StreamID]__`) so that a restore doesn't depend on stream names.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Header(Header),
+    Stream(Stream),
+    Entry(Entry),
+    Template(Template),
+    Schedule(Schedule),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackupOptions {
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BackupReport {
+    pub path: String,
+    pub schema_version: u32,
+    pub streams: usize,
+    pub entries: usize,
+    pub templates: usize,
+    pub schedules: usize,
+    /// Records that couldn't be decoded and were left out of the export (`<tree>/<key>`, as in
+    /// the `quarantine` tree). The database is left as is.
+    #[serde(default)]
+    pub skipped: Vec<String>,
+}
+
+impl DB {
+    /// Writes the records of `tree` as `record`s and returns their number. Records that can't be
+    /// decoded are skipped and reported in `report`, exports never writing to the database.
+    fn export_tree<T: DeserializeOwned, W: Write>(
+        &self,
+        w: &mut W,
+        tree: &sled::Tree,
+        record: fn(T) -> Record,
+        report: &mut BackupReport,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for x in tree.iter() {
+            let (k, v) = x?;
+            match deserialize::<T>(&v) {
+                Ok(t) => {
+                    writeln!(w, "{}", serde_json::to_string(&record(t))?)?;
+                    count += 1;
+                }
+                Err(err) => {
+                    let key = format!(
+                        "{}/{}",
+                        String::from_utf8_lossy(&tree.name()),
+                        String::from_utf8_lossy(&k)
+                    );
+                    tracing::warn!(
+                        key = key.as_str(),
+                        error = format!("{}", err).as_str(),
+                        "export"
+                    );
+                    report.skipped.push(key);
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Dumps every stream, entry, template and schedule as JSON Lines, preceded by a `Header`.
+    pub fn export_jsonl<W: Write>(&self, mut w: W) -> Result<BackupReport> {
+        let mut report = BackupReport {
+            schema_version: self.schema_version()?,
+            ..BackupReport::default()
+        };
+
+        let header = Record::Header(Header {
+            format: String::from("dump"),
+            schema_version: report.schema_version,
+            created: time::now(),
+        });
+        writeln!(w, "{}", serde_json::to_string(&header)?)?;
+
+        report.streams = self.export_tree(&mut w, &self.streams, Record::Stream, &mut report)?;
+        report.entries = self.export_tree(&mut w, &self.entries, Record::Entry, &mut report)?;
+        report.templates =
+            self.export_tree(&mut w, &self.templates, Record::Template, &mut report)?;
+        report.schedules =
+            self.export_tree(&mut w, &self.schedules, Record::Schedule, &mut report)?;
+        w.flush()?;
+
+        Ok(report)
+    }
+
+    /// Restores a JSONL backup. The database must be fresh (no entries and no stream other than
+    /// `{Inbox}`) as streams and entries are restored as is, ids included. The whole backup is
+    /// read and checked before anything is written, so that a bad record leaves the database
+    /// fresh. Entries are indexed once all of them are stored, so that links resolve whatever
+    /// their order in the backup.
+    pub fn import_jsonl<R: BufRead>(&self, r: R) -> Result<BackupReport> {
+        if !self.entries.is_empty()
+            || self.streams.iter().keys().any(|k| match k {
+                Ok(k) => k != INBOX_ID.as_bytes(),
+                Err(_) => true,
+            })
+        {
+            return Err(anyhow!("Backups can only be imported in a fresh database"));
+        }
+
+        let mut report = BackupReport::default();
+        let mut lines = r.lines();
+
+        match lines.next() {
+            Some(line) => match serde_json::from_str(&line?)? {
+                Record::Header(h) => {
+                    if h.schema_version > SCHEMA_VERSION {
+                        return Err(anyhow!(
+                            "Unsupported backup schema version: {} (current: {})",
+                            h.schema_version,
+                            SCHEMA_VERSION
+                        ));
+                    }
+                    report.schema_version = h.schema_version;
+                }
+                _ => return Err(anyhow!("Missing backup header")),
+            },
+            None => return Err(anyhow!("Empty backup")),
+        }
+
+        let mut streams: Vec<Stream> = vec![];
+        let mut entries: Vec<Entry> = vec![];
+        let mut templates: Vec<Template> = vec![];
+        let mut schedules: Vec<Schedule> = vec![];
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line)
+                .map_err(|err| anyhow!("Invalid record on line {}: {}", i + 2, err))?
+            {
+                Record::Header(_) => {
+                    return Err(anyhow!("Unexpected header on line {}", i + 2));
+                }
+                Record::Stream(s) => streams.push(s),
+                Record::Entry(mut e) => {
+                    // Backups from before `fields`, `updated` and `tz_offset` were introduced
+                    // don't carry them.
+                    if e.tz_offset.is_none() {
+                        e.tz_offset = Some(time::local_offset(e.created));
+                    }
+                    let (meta, fields) = fields::parse(&e.meta, e.date());
+                    e.meta = meta;
+                    e.fields = fields;
+                    if e.updated == 0 {
+                        e.updated = e.created;
+                    }
+                    entries.push(e);
+                }
+                Record::Template(t) => {
+                    if t.name.trim().is_empty() {
+                        return Err(anyhow!("Invalid template name on line {}", i + 2));
+                    }
+                    templates.push(t);
+                }
+                Record::Schedule(s) => schedules.push(s),
+            }
+        }
+
+        // Streams are stored as is (legacy databases may hold several streams with the same
+        // name, which `insert_stream` would reject) and the catalog is rebuilt from them.
+        for s in streams.iter() {
+            self.streams.insert(s.id.as_bytes(), serialize(s)?)?;
+        }
+        self.reload_streams()?;
+        report.streams = streams.len();
+
+        for e in entries.iter() {
+            self.entries.insert(e.id.as_bytes(), serialize(e)?)?;
+            self.titles.insert(sort::title_key(e), &[])?;
+        }
+        for e in entries.iter() {
+            self.index_entry(e, None)?;
+        }
+        report.entries = entries.len();
+
+        for t in templates.iter() {
+            self.set_template(t)?;
+        }
+        report.templates = templates.len();
+        // Stored as is to keep `last_run`, which `set_schedule` would reset.
+        for s in schedules.iter() {
+            self.schedules.insert(s.name.as_bytes(), serialize(s)?)?;
+        }
+        report.schedules = schedules.len();
+        self.entries.flush()?;
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let src = test_db();
+        test_entry(&src, "{Work/ProjectX} {Inbox}", "Foo", "Bar\nBaz");
+        // Links to entries further in the backup resolve on import.
+        let link = |title: &str, body: &str| test_entry(&src, "", title, body);
+        link("Before", "See [[After]]");
+        let after = link("After", "");
+        src.set_template(&Template {
+            name: String::from("Work"),
+            title: String::from("{{date}}"),
+            meta: String::from(""),
+            body: String::from("## Notes"),
+        })
+        .unwrap();
+
+        let mut out: Vec<u8> = vec![];
+        let report = src.export_jsonl(&mut out).unwrap();
+        assert_eq!(
+            (2, 3, 1, 0),
+            (
+                report.streams,
+                report.entries,
+                report.templates,
+                report.skipped.len()
+            )
+        );
+
+        let tgt = test_db();
+        let report = tgt.import_jsonl(&out[..]).unwrap();
+        assert_eq!(
+            (SCHEMA_VERSION, 2, 3),
+            (report.schema_version, report.streams, report.entries)
+        );
+
+        assert_eq!(src.list_streams().unwrap(), tgt.list_streams().unwrap());
+        assert_eq!(src.list_templates().unwrap(), tgt.list_templates().unwrap());
+        let (_, src_entries) = src.list_entries("", 0, 10).unwrap();
+        let (_, tgt_entries) = tgt.list_entries("", 0, 10).unwrap();
+        assert_eq!(
+            serde_json::to_string(&src_entries).unwrap(),
+            serde_json::to_string(&tgt_entries).unwrap()
+        );
+
+        assert_eq!(1, tgt.get_backlinks(&after.id).unwrap().len());
+        assert_eq!(0, tgt.links.scan_prefix(b"pending").count());
+
+        assert!(tgt.import_jsonl(&out[..]).is_err());
+
+        // A bad record leaves the database fresh, so that the import can be retried.
+        let tgt = test_db();
+        let mut bad = out.clone();
+        bad.extend_from_slice(b"{\"type\":\"entry\",\"id\":1}\n");
+        assert!(tgt.import_jsonl(&bad[..]).is_err());
+        assert!(tgt.entries.is_empty());
+        assert_eq!(3, tgt.import_jsonl(&out[..]).unwrap().entries);
+        assert_eq!(3, tgt.list_entries("", 0, 10).unwrap().0);
+
+        // Records that can't be decoded are skipped rather than failing the backup, and left in
+        // place.
+        src.entries.insert("1626000000-bad", vec![1, 2, 3]).unwrap();
+        let report = src.export_jsonl(&mut vec![]).unwrap();
+        assert_eq!(3, report.entries);
+        assert_eq!(vec!["entries/1626000000-bad"], report.skipped);
+        assert_eq!(0, src.quarantine.len());
+        assert!(src.entries.contains_key("1626000000-bad").unwrap());
+    }
+
+    #[test]
+    fn test_jsonl_duplicate_stream_names() {
+        // Legacy databases may hold several streams with the same name.
+        let src = test_db();
+        for id in ["1626000000-a", "1626000001-b"] {
+            let s = Stream {
+                id: String::from(id),
+                meta: String::from(""),
+                name: String::from("Work"),
+            };
+            src.streams.insert(id, serialize(&s).unwrap()).unwrap();
+        }
+        let mut out: Vec<u8> = vec![];
+        src.export_jsonl(&mut out).unwrap();
+
+        let tgt = test_db();
+        assert_eq!(3, tgt.import_jsonl(&out[..]).unwrap().streams);
+        assert_eq!(3, tgt.list_streams().unwrap().len());
+        assert_eq!(
+            Some(String::from("1626000000-a")),
+            tgt.stream_id_by_name("Work").unwrap()
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::models::{parent_names, Stream};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// In-memory index of the `streams` tree, kept coherent by `DB::insert_stream` and
+/// `DB::delete_stream`, so that resolving streams doesn't require a scan of the tree.
+#[derive(Debug, Default, Clone)]
+pub struct Catalog {
+    streams: HashMap<String, Stream>,
+    names: HashMap<String, String>,
+    children: HashMap<String, BTreeSet<String>>,
+    /// Names directly below a name in the hierarchy, whether or not a stream carries them, with
+    /// the number of streams at or below each of them.
+    below: HashMap<String, BTreeMap<String, usize>>,
+}
+
+/// Name of the parent of `name` in the stream hierarchy (`Foo` for `Foo/Bar`), if any.
+fn parent_name(name: &str) -> Option<String> {
+    let p = parent_names(name);
+    if p.len() >= 2 {
+        Some(p[p.len() - 2].clone())
+    } else {
+        None
+    }
+}
+
+impl Catalog {
+    pub fn new(streams: Vec<Stream>) -> Self {
+        let mut c = Catalog::default();
+        streams.into_iter().for_each(|s| c.insert(s));
+        c
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Stream> {
+        self.streams.get(id)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Stream> {
+        self.names.get(name).and_then(|id| self.streams.get(id))
+    }
+
+    /// Returns the direct children of the stream named `name` in the hierarchy, whether or not a
+    /// stream with that name exists.
+    pub fn children(&self, name: &str) -> Vec<&Stream> {
+        match self.children.get(name) {
+            Some(ids) => ids.iter().filter_map(|id| self.streams.get(id)).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns all the streams below `name` in the hierarchy, including the ones whose
+    /// intermediate parents don't exist (`Foo/Bar/Baz` without `Foo/Bar` for `Foo`).
+    pub fn descendants(&self, name: &str) -> Vec<&Stream> {
+        let mut streams = vec![];
+        let mut names = vec![String::from(name)];
+        while let Some(n) = names.pop() {
+            streams.extend(self.children(&n));
+            if let Some(b) = self.below.get(&n) {
+                names.extend(b.keys().cloned());
+            }
+        }
+        streams
+    }
+
+    pub fn all(&self) -> Vec<&Stream> {
+        self.streams.values().collect()
+    }
+
+    pub fn insert(&mut self, stream: Stream) {
+        self.remove(&stream.id.clone());
+        self.names
+            .entry(stream.name.clone())
+            .or_insert_with(|| stream.id.clone());
+        if let Some(p) = parent_name(&stream.name) {
+            self.children
+                .entry(p)
+                .or_default()
+                .insert(stream.id.clone());
+        }
+        for w in parent_names(&stream.name).windows(2) {
+            *self
+                .below
+                .entry(w[0].clone())
+                .or_default()
+                .entry(w[1].clone())
+                .or_default() += 1;
+        }
+        self.streams.insert(stream.id.clone(), stream);
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Stream> {
+        let s = self.streams.remove(id)?;
+        if self.names.get(&s.name) == Some(&s.id) {
+            self.names.remove(&s.name);
+            // Another stream may carry the same name, index it instead.
+            if let Some(o) = self.streams.values().find(|o| o.name == s.name) {
+                self.names.insert(o.name.clone(), o.id.clone());
+            }
+        }
+        if let Some(p) = parent_name(&s.name) {
+            if let Some(c) = self.children.get_mut(&p) {
+                c.remove(&s.id);
+                if c.is_empty() {
+                    self.children.remove(&p);
+                }
+            }
+        }
+        for w in parent_names(&s.name).windows(2) {
+            if let Some(b) = self.below.get_mut(&w[0]) {
+                if let Some(c) = b.get_mut(&w[1]) {
+                    *c -= 1;
+                    if *c == 0 {
+                        b.remove(&w[1]);
+                    }
+                }
+                if b.is_empty() {
+                    self.below.remove(&w[0]);
+                }
+            }
+        }
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(id: &str, name: &str) -> Stream {
+        Stream {
+            id: String::from(id),
+            meta: String::from(""),
+            name: String::from(name),
+        }
+    }
+
+    #[test]
+    fn test_catalog() {
+        let mut c = Catalog::new(vec![
+            stream("1", "Work"),
+            stream("2", "Work/ProjectX"),
+            stream("3", "Work/ProjectX/Design"),
+            stream("4", "Home/Garden"),
+        ]);
+        assert_eq!("2", c.by_name("Work/ProjectX").unwrap().id);
+        assert_eq!(
+            vec!["2"],
+            c.children("Work")
+                .iter()
+                .map(|s| s.id.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2, c.descendants("Work").len());
+        assert_eq!(1, c.descendants("Home").len());
+        assert!(c.descendants("Work/ProjectX/Design").is_empty());
+        assert_eq!("4", c.children("Home")[0].id);
+
+        c.insert(stream("2", "Job/ProjectX"));
+        assert!(c.by_name("Work/ProjectX").is_none());
+        assert_eq!("3", c.descendants("Work")[0].id);
+        assert_eq!(1, c.descendants("Work/ProjectX").len());
+        assert!(c.children("Work").is_empty());
+        assert_eq!("2", c.by_name("Job/ProjectX").unwrap().id);
+
+        c.insert(stream("5", "Job/ProjectX"));
+        assert_eq!("2", c.by_name("Job/ProjectX").unwrap().id);
+        c.remove("2");
+        assert_eq!("5", c.by_name("Job/ProjectX").unwrap().id);
+    }
+}
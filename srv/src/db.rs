@@ -1,18 +1,54 @@
-use crate::models::{Entry, EntryCreation, Stream};
-use anyhow::Result;
+use crate::catalog::Catalog;
+use crate::fields;
+use crate::fuzzy;
+use crate::migrations;
+use crate::models::{Entry, EntryCreation, Field, Quarantined, Stream};
+use crate::postprocess;
+use crate::query::{self, Query};
+use crate::sort::Sort;
+use crate::tasks;
+use crate::time;
+use anyhow::{anyhow, Result};
 use bincode::{deserialize, serialize};
+use chrono::NaiveDate;
 use lazy_static::lazy_static;
 use nanoid::nanoid;
 use regex::{Captures, Regex};
-use std::time::SystemTime;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Version of the on-disk layout of `Entry` and `Stream` records, see `migrations`.
+pub const SCHEMA_VERSION: u32 = 8;
+
+/// Id of the `{Inbox}` stream, created by `DB::init`.
+pub const INBOX_ID: &str = "0-inbox";
 
 #[derive(Debug, Clone)]
 pub struct DB {
-    entries: sled::Tree,
-    streams: sled::Tree,
+    pub(crate) entries: sled::Tree,
+    pub(crate) streams: sled::Tree,
+    pub(crate) stream_names: sled::Tree,
+    pub(crate) tasks: sled::Tree,
+    pub(crate) fields: sled::Tree,
+    pub(crate) links: sled::Tree,
+    pub(crate) titles: sled::Tree,
+    pub(crate) by_created: sled::Tree,
+    pub(crate) by_updated: sled::Tree,
+    pub(crate) templates: sled::Tree,
+    pub(crate) schedules: sled::Tree,
+    pub(crate) stats: sled::Tree,
+    pub(crate) terms: sled::Tree,
+    pub(crate) meta: sled::Tree,
+    pub(crate) quarantine: sled::Tree,
+    pub(crate) catalog: Arc<RwLock<Catalog>>,
+    /// Held while a batch of the postprocessing pass runs (see `DB::postprocess`) and while streams
+    /// are merged (see `DB::merge_streams`).
+    pub(crate) postprocessing: Arc<Mutex<()>>,
 }
 
-pub fn extract_stream_names(s: &String) -> Vec<String> {
+pub fn extract_stream_names(s: &str) -> Vec<String> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"\{[^\{\}]+\}").unwrap();
     }
@@ -24,7 +60,20 @@ pub fn extract_stream_names(s: &String) -> Vec<String> {
         .collect::<Vec<_>>()
 }
 
-pub fn clean_stream_names(query: &String) -> String {
+/// Extracts the ids of the streams of an id-based `meta` (`_stream_id_[StreamID]__`).
+pub fn extract_stream_ids(meta: &str) -> Vec<String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"_stream_id_\[[^\{\[\]\}]+\]__").unwrap();
+    }
+    RE.captures_iter(meta)
+        .map(|c| {
+            let r = String::from(&c[0]);
+            r[12..r.len() - 3].to_string()
+        })
+        .collect::<Vec<_>>()
+}
+
+pub fn clean_stream_names(query: &str) -> String {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"\{[^\{\}]+\}").unwrap();
     }
@@ -36,91 +85,304 @@ pub fn clean_stream_names(query: &String) -> String {
     s
 }
 
-pub fn match_title(query: &String, entry: &Entry) -> bool {
+pub fn match_title(query: &str, entry: &Entry) -> bool {
     entry
         .title
         .to_lowercase()
         .contains(clean_stream_names(query).to_lowercase().as_str())
 }
 
-pub fn match_body(query: &String, entry: &Entry) -> bool {
+pub fn match_body(query: &str, entry: &Entry) -> bool {
     entry
         .body
         .to_lowercase()
         .contains(clean_stream_names(query).to_lowercase().as_str())
 }
 
+/// Moves a record that failed to decode from `tree` to the `quarantine` tree. The indexes that
+/// point to it are left as is: once the `DB` is opened, records are quarantined through
+/// `DB::quarantine_record`, which also removes them from the indexes, and the records quarantined
+/// by migrations are unindexed when it opens (see `DB::open_with`).
+pub fn quarantine(
+    quarantine: &sled::Tree,
+    tree: &sled::Tree,
+    key: &[u8],
+    data: &[u8],
+    error: &str,
+) -> Result<()> {
+    let tree_name = String::from_utf8_lossy(&tree.name()).to_string();
+    let q = Quarantined {
+        tree: tree_name.clone(),
+        key: String::from_utf8_lossy(key).to_string(),
+        error: String::from(error),
+        quarantined: time::now(),
+        data: data.to_vec(),
+    };
+    quarantine.insert([tree_name.as_bytes(), b"/", key].concat(), serialize(&q)?)?;
+    tree.remove(key)?;
+
+    tracing::warn!(
+        tree = q.tree.as_str(),
+        key = q.key.as_str(),
+        error,
+        "quarantine"
+    );
+    Ok(())
+}
+
+/// Joins the parts of a key of the index trees (`links`, `stats`, `terms`) with `\0` separators.
+pub(crate) fn key(parts: &[&str]) -> Vec<u8> {
+    parts.join("\0").into_bytes()
+}
+
+/// Updates the record `k` of `tree` atomically: `f` gets its current value, if any, and returns
+/// the new one, `None` removing the record. A record that can't be decoded is left as is and the
+/// error returned.
+pub(crate) fn update_record<T, F>(tree: &sled::Tree, k: &[u8], mut f: F) -> Result<()>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnMut(Option<T>) -> Option<T>,
+{
+    let mut res = Ok(());
+    tree.fetch_and_update(k, |old| {
+        res = Ok(());
+        match old.map(deserialize::<T>).transpose() {
+            Ok(v) => f(v).and_then(|v| serialize(&v).ok()),
+            Err(err) => {
+                res = Err(err);
+                old.map(|v| v.to_vec())
+            }
+        }
+    })?;
+    Ok(res?)
+}
+
+/// Checks that `name` can be used as a stream name: non-empty, without braces and with
+/// non-empty components (`Foo//Bar` or `Foo/` are rejected).
+pub fn validate_stream_name(name: &str) -> Result<()> {
+    if name.contains('{') || name.contains('}') {
+        return Err(anyhow!(
+            "Invalid stream name `{}`: braces are reserved",
+            name
+        ));
+    }
+    if name.split('/').any(|c| c.trim().is_empty()) {
+        return Err(anyhow!("Invalid stream name `{}`: empty component", name));
+    }
+    Ok(())
+}
+
+/// Checks that `id` can be used as an entry id: ids end up in file names (see
+/// `DB::export_markdown`) and in `\0` separated index keys (see `key`), so they must be non-empty
+/// and free of path separators, `..` and `\0`.
+pub fn validate_entry_id(id: &str) -> Result<()> {
+    if id.is_empty() || id.contains(['/', '\\', '\0']) || id.contains("..") {
+        return Err(anyhow!("Invalid entry id `{}`", id.escape_debug()));
+    }
+    Ok(())
+}
+
+/// Generates an id of the form `<secs>-<nanoid>`, `secs` being the creation time at the time the
+/// id is generated. Ids are opaque afterwards: entries can be backdated without changing their id
+/// (they are ordered through the `by_created` index), so their creation time is never read back
+/// from their id.
+pub fn generate_id(secs: u64) -> String {
+    format!("{}-{}", secs, nanoid!())
+}
+
 impl DB {
+    /// Opens the `DB` at `path`, migrating it to `SCHEMA_VERSION` if needed. A snapshot of the
+    /// database is taken next to it before running any migration. If it is pending, the
+    /// postprocessing pass is run in the background (see `DB::postprocess_in_background`).
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        let db = sled::open(path)?;
+        let db = sled::open(path.as_ref())?;
+        let d = DB::open_with(db, Some(path.as_ref()))?;
+        d.postprocess_in_background()?;
+        Ok(d)
+    }
+
+    /// Opens the `DB` on top of an already opened sled database. Mostly useful to run on a
+    /// temporary database (see `sled::Config::temporary`), no snapshot is taken before migrating.
+    pub fn open(db: sled::Db) -> Result<Self> {
+        DB::open_with(db, None)
+    }
+
+    /// Migrates `db` (see `migrations::migrate`) and opens the `DB` on top of it. The records
+    /// quarantined by the migrations are then removed from the indexes.
+    fn open_with(db: sled::Db, path: Option<&std::path::Path>) -> Result<Self> {
+        let quarantined = |db: &sled::Db| -> Result<HashSet<sled::IVec>> {
+            Ok(db
+                .open_tree("quarantine")?
+                .iter()
+                .keys()
+                .collect::<sled::Result<_>>()?)
+        };
+        let before = quarantined(&db)?;
+        migrations::migrate(&db, path)?;
+        let after = quarantined(&db)?;
+
+        let d = DB::init_with(db)?;
+        let mut streams = false;
+        for k in after.difference(&before) {
+            let k = String::from_utf8_lossy(k);
+            match k.split_once('/') {
+                Some(("entries", id)) => d.unindex_entry_id(id)?,
+                Some(("streams", _)) => streams = true,
+                _ => (),
+            }
+        }
+        if streams {
+            d.index_stream_names()?;
+        }
+        Ok(d)
+    }
+
+    fn init_with(db: sled::Db) -> Result<Self> {
         let entries = db.open_tree("entries")?;
         let streams = db.open_tree("streams")?;
+        let stream_names = db.open_tree("stream_names")?;
+        let tasks = db.open_tree("tasks")?;
+        let fields = db.open_tree("fields")?;
+        let links = db.open_tree("links")?;
+        let titles = db.open_tree("titles")?;
+        let by_created = db.open_tree("by_created")?;
+        let by_updated = db.open_tree("by_updated")?;
+        let templates = db.open_tree("templates")?;
+        let schedules = db.open_tree("schedules")?;
+        let stats = db.open_tree("stats")?;
+        let terms = db.open_tree("terms")?;
+        let meta = db.open_tree("meta")?;
+        let quarantine = db.open_tree("quarantine")?;
 
-        let d = DB { entries, streams };
+        let d = DB {
+            entries,
+            streams,
+            stream_names,
+            tasks,
+            fields,
+            links,
+            titles,
+            by_created,
+            by_updated,
+            templates,
+            schedules,
+            stats,
+            terms,
+            meta,
+            quarantine,
+            catalog: Arc::new(RwLock::new(Catalog::default())),
+            postprocessing: Arc::new(Mutex::new(())),
+        };
         d.init()?;
 
         Ok(d)
     }
 
+    /// Returns the schema version recorded in the `meta` tree.
+    pub fn schema_version(&self) -> Result<u32> {
+        migrations::stored_version(&self.meta)?.ok_or_else(|| anyhow!("Missing schema version"))
+    }
+
     fn init(&self) -> Result<()> {
         // Insert `{Inbox}` with special ID `_stream_id_[0-inbox]__`.
         let s = Stream {
-            id: String::from("0-inbox"),
+            id: String::from(INBOX_ID),
             meta: String::from(""),
             name: String::from("Inbox"),
         };
         self.streams
             .insert(s.id.clone().as_bytes(), serialize(&s).unwrap())?;
+        self.stream_names
+            .insert(s.name.as_bytes(), s.id.as_bytes())?;
 
-        // TODO(spolu) postprocess and update all existing entries.
-        let entries = self
-            .entries
-            .iter()
-            .filter_map(|x| {
-                let id =
-                    String::from(std::str::from_utf8(&x.clone().unwrap().0.to_owned()).unwrap());
-                match deserialize(&x.clone().unwrap().1.to_owned()) {
-                    Ok(e) => Some(e),
-                    _ => {
-                        // Deserialization failed, remove the id.
-                        self.entries.remove(id.as_bytes()).unwrap();
-                        None
-                    }
+        self.load_catalog()?;
+
+        // Entries are postprocessed separately, see `DB::postprocess`.
+        if self.entries.is_empty() {
+            postprocess::mark_done(&self.meta)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the catalog from the `streams` tree, moving aside the streams that can't be decoded
+    /// as the rest of the code expects all of them to be valid.
+    fn load_catalog(&self) -> Result<()> {
+        let mut streams: Vec<Stream> = vec![];
+        let mut quarantined = false;
+        for x in self.streams.iter() {
+            let (k, v) = x?;
+            match deserialize::<Stream>(&v) {
+                Ok(s) => streams.push(s),
+                Err(err) => {
+                    quarantine(&self.quarantine, &self.streams, &k, &v, &format!("{}", err))?;
+                    quarantined = true;
                 }
-            })
-            .collect::<Vec<Entry>>();
-        entries.iter().for_each(|e| {
-            self.insert_entry(e).unwrap();
-        });
+            }
+        }
+        *self.catalog.write().unwrap() = Catalog::new(streams);
+        if quarantined {
+            self.index_stream_names()?;
+        }
+        Ok(())
+    }
 
+    /// Rebuilds the `stream_names` tree from the catalog. If several streams share a name, the
+    /// first one (by id) is indexed, as in `migrations`.
+    fn index_stream_names(&self) -> Result<()> {
+        self.stream_names.clear()?;
+        let catalog = self.catalog.read().unwrap();
+        for s in catalog.all() {
+            self.index_stream_name(&catalog, &s.name)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the catalog and the `stream_names` tree after streams were written directly to the
+    /// `streams` tree (eg: when restoring a backup).
+    pub(crate) fn reload_streams(&self) -> Result<()> {
+        self.load_catalog()?;
+        self.index_stream_names()
+    }
+
+    /// Moves a record of `tree` that failed to decode to the `quarantine` tree (see `quarantine`)
+    /// and removes it from the indexes of the tree, so that none of them points to it.
+    pub(crate) fn quarantine_record(
+        &self,
+        tree: &sled::Tree,
+        key: &[u8],
+        data: &[u8],
+        error: &str,
+    ) -> Result<()> {
+        quarantine(&self.quarantine, tree, key, data, error)?;
+        if tree.name() == self.entries.name() {
+            self.unindex_entry_id(&String::from_utf8_lossy(key))?;
+        } else if tree.name() == self.streams.name() {
+            let mut catalog = self.catalog.write().unwrap();
+            if let Some(s) = catalog.remove(&String::from_utf8_lossy(key)) {
+                self.index_stream_name(&catalog, &s.name)?;
+            }
+        }
         Ok(())
     }
 
     /// Finds a stream by name or create a new one with this name if it does not exist (if `create`
     /// is true) otherwise return `None`.
-    fn stream_by_name(&self, name: &String, create: bool) -> Result<Option<Stream>> {
-        match self.streams.iter().find_map(|x| {
-            // let id = std::str::from_utf8(&x.clone().unwrap().0.to_owned());
-            let s: Stream = deserialize(&x.clone().unwrap().1.to_owned()).unwrap();
-            if s.name == name.clone() {
-                Some(s)
-            } else {
-                None
-            }
-        }) {
+    pub fn stream_by_name(&self, name: &str, create: bool) -> Result<Option<Stream>> {
+        let found = match self.stream_id_by_name(name)? {
+            Some(id) => self.get_stream(&id)?,
+            None => None,
+        };
+        match found {
             None => {
                 if create {
-                    let now = SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
+                    let now = time::now();
                     let s = Stream {
-                        id: format!("{}-{}", now, nanoid!()),
+                        id: generate_id(now),
                         meta: String::from(""),
-                        name: name.clone(),
+                        name: String::from(name),
                     };
-                    self.streams
-                        .insert(s.id.clone().as_bytes(), serialize(&s).unwrap())?;
+                    self.insert_stream(&s)?;
                     Ok(Some(s))
                 } else {
                     Ok(None)
@@ -130,88 +392,77 @@ impl DB {
         }
     }
 
-    fn streams_from_query(&self, query: &String) -> Result<Vec<Stream>> {
+    /// Parses `query` (see `query::parse`), `key:value` tokens being filters only for the fields
+    /// of some entry.
+    pub(crate) fn parse_query(&self, query: &str) -> Query {
+        query::parse(query, &|k| self.is_known_field(k).unwrap_or(false))
+    }
+
+    /// Returns the streams of `query`, unknown ones being left out. In fuzzy queries (see
+    /// `fuzzy`), the closest stream is used for the unknown names.
+    pub(crate) fn streams_from_query(&self, query: &str) -> Result<Vec<Stream>> {
         let stream_names = extract_stream_names(query);
+        let fuzzy = self.parse_query(query).fuzzy;
         let streams = stream_names
             .iter()
-            .map(|sn| self.stream_by_name(sn, false))
+            .map(|sn| match fuzzy {
+                true => self.stream_by_name_fuzzy(sn),
+                false => self.stream_by_name(sn, false),
+            })
             .collect::<Result<Vec<_>>>()?;
 
-        Ok(streams.into_iter().filter_map(|s| s).collect::<Vec<_>>())
+        Ok(streams.into_iter().flatten().collect::<Vec<_>>())
     }
 
     /// `preprocess_meta` extracts the streams names from the `meta` string provided
     /// (`{StreamName}`), ensures that each stream exists and replace them with their id-based
-    /// format (`_stream_id_[StreamID]__`).
-    fn preprocess_meta(&self, meta: &String) -> Result<String> {
+    /// format (`_stream_id_[StreamID]__`). It also extracts the `key:value` fields and
+    /// normalizes them (see `fields::parse`), relative dates being resolved against `date`.
+    fn preprocess_meta(
+        &self,
+        meta: &str,
+        date: NaiveDate,
+    ) -> Result<(String, BTreeMap<String, Field>)> {
+        let (meta, fields) = fields::parse(meta, date);
+        let meta = meta.as_str();
         let stream_names = extract_stream_names(meta);
         let streams = stream_names
             .iter()
             .map(|sn| self.stream_by_name(sn, true))
             .collect::<Result<Vec<_>>>()?;
 
-        let mut m = meta.clone();
-        streams.iter().for_each(|s| match s {
-            Some(s) => {
-                m = m.replace(
-                    format!("{{{}}}", s.name.as_str()).as_str(),
-                    format!("_stream_id_[{}]__", s.id.as_str()).as_str(),
-                );
-            }
-            _ => {}
+        let mut m = meta.to_string();
+        streams.iter().flatten().for_each(|s| {
+            m = m.replace(
+                format!("{{{}}}", s.name.as_str()).as_str(),
+                format!("_stream_id_[{}]__", s.id.as_str()).as_str(),
+            );
         });
         // tracing::debug!(meta = m.as_str(), "preprocess_meta");
-        Ok(m)
+        Ok((m, fields))
     }
 
-    pub fn extract_streams_from_meta(&self, s: &String, parent_streams: bool) -> Vec<Stream> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"_stream_id_\[[^\{\[\]\}]+\]__").unwrap();
-        }
-        let stream_ids = RE
-            .captures_iter(s)
-            .map(|c| {
-                let r = String::from(&c[0]);
-                r[12..r.len() - 3].to_string()
-            })
-            .collect::<Vec<_>>();
+    pub fn extract_streams_from_meta(&self, s: &str, parent_streams: bool) -> Vec<Stream> {
+        let stream_ids = extract_stream_ids(s);
 
-        // First iterate on all sreams to match them by id from the ids extracted from `meta`.
-        let streams = self
-            .streams
+        // First match the streams by id from the ids extracted from `meta`.
+        let catalog = self.catalog.read().unwrap();
+        let streams = stream_ids
             .iter()
-            .filter_map(|x| {
-                // let id = std::str::from_utf8(&x.clone().unwrap().0.to_owned());
-                let s: Stream = deserialize(&x.clone().unwrap().1.to_owned()).unwrap();
-                if stream_ids.iter().any(|id| s.id == *id) {
-                    Some(s)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|id| catalog.get(id).cloned())
             .collect::<Vec<_>>();
 
         if parent_streams {
-            // If `parent_streams` is true, re-iterate on streams a second time and match streams
-            // whose names are parents of the streams extracted previously.
-            let mut parent_streams = self.streams
+            // If `parent_streams` is true, also match streams whose names are parents of the
+            // streams extracted previously.
+            let mut parent_streams = streams
                 .iter()
-                .filter_map(|x| {
-                    // let id = std::str::from_utf8(&x.clone().unwrap().0.to_owned());
-                    let sp: Stream = deserialize(&x.clone().unwrap().1.to_owned()).unwrap();
-                    if streams
-                        .iter()
-                        .any(|s| s.parent_names().iter().any(|n| sp.name == *n))
-                    {
-                        Some(sp)
-                    } else {
-                        None
-                    }
-                })
+                .flat_map(|s| s.parent_names())
+                .filter_map(|n| catalog.by_name(&n).cloned())
                 .collect::<Vec<_>>();
-                parent_streams.sort_unstable();
-                parent_streams.dedup();
-                parent_streams
+            parent_streams.sort_unstable();
+            parent_streams.dedup();
+            parent_streams
         } else {
             // Otherwise return the `streams` directly.
             streams
@@ -220,10 +471,10 @@ impl DB {
 
     /// `postprocess_meta` extracts the streams ids from the `meta` string provided
     /// (`_stream_id_[StreamID]__`), and replace them with their name (`{StreamName}`).
-    fn postprocess_meta(&self, meta: &String) -> Result<String> {
+    fn postprocess_meta(&self, meta: &str) -> Result<String> {
         let streams = self.extract_streams_from_meta(meta, false);
 
-        let mut m = meta.clone();
+        let mut m = meta.to_string();
         streams.iter().for_each(|s| {
             m = m.replace(
                 format!("_stream_id_[{}]__", s.id.as_str()).as_str(),
@@ -252,36 +503,157 @@ impl DB {
     }
 
     pub fn create_entry(&self, create: &EntryCreation) -> Result<Entry> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        // Entries can be backdated by passing their creation time.
+        let created = create.created.unwrap_or_else(time::now);
 
         let entry = Entry {
-            id: format!("{}-{}", now, nanoid!()),
-            created: now,
+            id: generate_id(created),
+            created,
             title: create.title.clone(),
             meta: create.meta.clone(),
             body: create.body.clone(),
+            tz_offset: Some(
+                create
+                    .tz_offset
+                    .unwrap_or_else(|| time::local_offset(created)),
+            ),
+            ..Entry::default()
         };
 
-        self.insert_entry(&entry).unwrap();
+        self.insert_entry(&entry)?;
 
-        Ok(entry)
+        Ok(self.get_entry(&entry.id)?.unwrap_or(entry))
     }
 
     pub fn insert_entry(&self, update: &Entry) -> Result<()> {
-        let meta = self.preprocess_meta(&update.meta)?;
+        self.write_entry(update, false)
+    }
 
-        let entry = Entry {
-            meta,
-            ..update.clone()
+    /// Inserts `entry` keeping its `updated` time if set (eg: when restoring it from an export),
+    /// instead of moving it to now if its content changed.
+    pub fn restore_entry(&self, entry: &Entry) -> Result<()> {
+        self.write_entry(entry, entry.updated > 0)
+    }
+
+    fn write_entry(&self, update: &Entry, keep_updated: bool) -> Result<()> {
+        // Retried if the entry is written concurrently.
+        while !self.try_write_entry(update, keep_updated, None)? {}
+        Ok(())
+    }
+
+    /// Writes back `entry` as read from its record `raw` (eg: by the postprocessing pass), unless
+    /// the record changed meanwhile: the entry was then written, and indexed, by someone else.
+    /// Returns whether the entry was written.
+    pub(crate) fn rewrite_entry(&self, entry: &Entry, raw: &sled::IVec) -> Result<bool> {
+        self.try_write_entry(entry, false, Some(raw))
+    }
+
+    /// Writes `update` if the record of the entry is still `expected`, or the one read before
+    /// preparing the update if `None`, so that concurrent writes are never lost. Returns whether
+    /// the entry was written.
+    fn try_write_entry(
+        &self,
+        update: &Entry,
+        keep_updated: bool,
+        expected: Option<&sled::IVec>,
+    ) -> Result<bool> {
+        let raw = self.entries.get(update.id.as_bytes())?;
+        if expected.is_some() && raw.as_ref() != expected {
+            return Ok(false);
+        }
+        let previous = match &raw {
+            Some(v) => deserialize::<Entry>(v).ok(),
+            None => None,
+        };
+        let mut entry = update.clone();
+
+        // Entries without a UTC offset (eg: imported) keep their previous one or get the local
+        // one, so that their date is stable.
+        if entry.tz_offset.is_none() {
+            entry.tz_offset = match &previous {
+                Some(p) if p.tz_offset.is_some() => p.tz_offset,
+                _ => Some(time::local_offset(entry.created)),
+            };
+        }
+        let (meta, fields) = self.preprocess_meta(&entry.meta, entry.date())?;
+        entry.meta = meta;
+        entry.fields = fields;
+
+        // `updated` only moves when the content of the entry changes. New entries keep the time
+        // they carry if any (eg: when imported) or their creation time.
+        entry.updated = match &previous {
+            _ if keep_updated => entry.updated,
+            Some(p)
+                if p.created == entry.created
+                    && p.title == entry.title
+                    && p.meta == entry.meta
+                    && p.body == entry.body =>
+            {
+                p.updated
+            }
+            Some(_) => time::now(),
+            None if entry.updated > 0 => entry.updated,
+            None => entry.created,
         };
 
-        self.entries.insert(
-            entry.id.clone().as_bytes(),
-            serialize(&entry).unwrap(),
-        )?;
+        if self
+            .entries
+            .compare_and_swap(entry.id.as_bytes(), raw, Some(serialize(&entry)?))?
+            .is_err()
+        {
+            return Ok(false);
+        }
+        self.index_entry(&entry, previous.as_ref())?;
+        Ok(true)
+    }
+
+    /// Updates all the indexes of `entry` (id-based, as stored in `entries`) replacing
+    /// `previous`. Every write to `entries` outside of `insert_entry` must go through it.
+    pub(crate) fn index_entry(&self, entry: &Entry, previous: Option<&Entry>) -> Result<()> {
+        tasks::index(&self.tasks, &entry.id, &entry.body)?;
+        self.index_fields(entry, previous)?;
+        self.index_times(entry, previous)?;
+        self.index_stats(entry)?;
+        self.index_terms(entry)?;
+        self.index_links(entry, previous)?;
+        Ok(())
+    }
+
+    /// Removes `entry` from all the indexes, see `index_entry`.
+    pub(crate) fn unindex_entry(&self, entry: &Entry) -> Result<()> {
+        self.tasks.remove(entry.id.as_bytes())?;
+        self.unindex_fields(entry)?;
+        self.unindex_times(entry)?;
+        self.unindex_stats(&entry.id)?;
+        self.unindex_terms(&entry.id)?;
+        self.unindex_links(entry)?;
+        Ok(())
+    }
+
+    /// Removes the entry `id`, whose record can't be decoded, from all the indexes (see
+    /// `unindex_entry`). The indexes whose keys depend on the content of the entry are scanned.
+    pub(crate) fn unindex_entry_id(&self, id: &str) -> Result<()> {
+        let suffix = [b"\0", id.as_bytes()].concat();
+        for tree in [&self.fields, &self.titles] {
+            for k in tree.iter().keys() {
+                let k = k?;
+                if k.ends_with(&suffix) {
+                    tree.remove(&k)?;
+                }
+            }
+        }
+        for tree in [&self.by_created, &self.by_updated] {
+            for k in tree.iter().keys() {
+                let k = k?;
+                if k.len() > 8 && &k[8..] == id.as_bytes() {
+                    tree.remove(&k)?;
+                }
+            }
+        }
+        self.tasks.remove(id.as_bytes())?;
+        self.unindex_stats(id)?;
+        self.unindex_terms(id)?;
+        self.unlink_entry(id)?;
         Ok(())
     }
 
@@ -298,120 +670,440 @@ impl DB {
     }
 
     pub fn delete_entry(&self, id: &String) -> Result<()> {
-        self.entries.remove(id.as_bytes())?;
+        if let Some(v) = self.entries.remove(id.as_bytes())? {
+            if let Ok(e) = deserialize::<Entry>(&v) {
+                self.unindex_entry(&e)?;
+            }
+        }
+        self.tasks.remove(id.as_bytes())?;
         Ok(())
     }
 
     pub fn list_entries(
         &self,
-        query: &String,
+        query: &str,
         offset: usize,
         limit: usize,
     ) -> Result<(usize, Vec<Entry>)> {
+        self.list_entries_sorted(query, Sort::default(), offset, limit)
+    }
+
+    /// Returns a matcher of the (id-based) entries selected by `query`, as listed by
+    /// `list_entries`. It returns the distance of the match for fuzzy queries (see `fuzzy::score`),
+    /// 0 otherwise, and `None` for entries not selected.
+    pub(crate) fn entry_matcher(
+        &self,
+        query: &str,
+    ) -> Result<impl Fn(&Entry) -> Option<usize> + '_> {
         let query_streams = self.streams_from_query(query)?;
+        let q = self.parse_query(query);
+        let today = time::today();
 
-        let all_entries: Vec<Entry> = self
-            .entries
-            .iter()
-            .rev()
-            .filter_map(|x| {
-                // let id = std::str::from_utf8(&x.clone().unwrap().0.to_owned());
-                let mut e: Entry = deserialize(&x.clone().unwrap().1.to_owned()).unwrap();
-                if self.match_meta(&query_streams, &e, true)
-                    && (match_title(&query, &e) || match_body(&query, &e))
-                {
-                    e.meta = self.postprocess_meta(&e.meta).unwrap();
-                    Some(e)
-                } else {
-                    None
+        // With `is:task` or `is:open-task`, only the entries of the `tasks` index are considered.
+        let mut with_tasks: Option<HashSet<sled::IVec>> = None;
+        for i in q.is.iter() {
+            match i.as_str() {
+                "task" if with_tasks.is_none() => {
+                    with_tasks = Some(self.entries_with_tasks(false)?)
                 }
-            })
-            .collect::<Vec<_>>();
-        let total = all_entries.len();
+                "task" => (),
+                "open-task" => with_tasks = Some(self.entries_with_tasks(true)?),
+                _ => (),
+            }
+        }
 
-        let entries = all_entries
-            .into_iter()
-            .skip(offset)
-            .take(limit)
-            .collect::<Vec<_>>();
+        Ok(move |e: &Entry| {
+            if with_tasks
+                .as_ref()
+                .is_some_and(|t| !t.contains(e.id.as_bytes()))
+                || !self.match_meta(&query_streams, e, true)
+                || !q.matches_fields(e, today)
+            {
+                return None;
+            }
+            if q.fuzzy {
+                fuzzy::score(&q.text, &format!("{}\n{}", e.title, e.body))
+            } else if match_title(&q.text, e) || match_body(&q.text, e) {
+                Some(0)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Lists the entries matching `query` in the order of `sort`, see `sort`.
+    pub fn list_entries_sorted(
+        &self,
+        query: &str,
+        sort: Sort,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<Entry>)> {
+        let matcher = self.entry_matcher(query)?;
+        let fuzzy = self.parse_query(query).fuzzy;
+
+        // Entries come in `sort` order from the index: only the requested page is kept, except for
+        // fuzzy queries whose closer matches come first.
+        let mut total = 0;
+        let mut entries = vec![];
+        let mut scored: Vec<(usize, Entry)> = vec![];
+        for v in self.sorted_entries(sort) {
+            let e: Entry = deserialize(&v?)?;
+            let d = match matcher(&e) {
+                Some(d) => d,
+                None => continue,
+            };
+            if fuzzy {
+                scored.push((d, e));
+            } else if total >= offset && entries.len() < limit {
+                entries.push(e);
+            }
+            total += 1;
+        }
+        if fuzzy {
+            scored.sort_by_key(|(d, _)| *d);
+            entries = scored
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|(_, e)| e)
+                .collect();
+        }
+        for e in entries.iter_mut() {
+            e.meta = self.postprocess_meta(&e.meta)?;
+        }
 
         Ok((total, entries))
     }
 
+    /// Returns the first `limit` entries matching `query` in the order of `sort`. Unlike
+    /// `list_entries_sorted`, it stops at the last of them instead of counting all the matching
+    /// entries, except for fuzzy queries whose matches are ordered by distance.
+    pub fn first_entries(&self, query: &str, sort: Sort, limit: usize) -> Result<Vec<Entry>> {
+        if self.parse_query(query).fuzzy {
+            return Ok(self.list_entries_sorted(query, sort, 0, limit)?.1);
+        }
+        let matcher = self.entry_matcher(query)?;
+
+        let mut entries = vec![];
+        for v in self.sorted_entries(sort) {
+            if entries.len() >= limit {
+                break;
+            }
+            let mut e: Entry = deserialize(&v?)?;
+            if matcher(&e).is_some() {
+                e.meta = self.postprocess_meta(&e.meta)?;
+                entries.push(e);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the entries matching `query` whose date is between `from` and `to` included, by
+    /// creation time. Only the entries created around the range are considered, through the
+    /// `by_created` index.
+    pub fn entries_between(
+        &self,
+        query: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<Entry>> {
+        let matcher = self.entry_matcher(query)?;
+
+        // Entries of a day were created within a day of its start in UTC, whatever the time zone
+        // of their author.
+        let start = (time::day_start(from, 0).saturating_sub(86400)).to_be_bytes();
+        let end = (time::day_start(to, 0) + 2 * 86400).to_be_bytes();
+        let mut entries = vec![];
+        for k in self.by_created.range(start..end).keys() {
+            let k = k?;
+            if let Some(v) = self.entries.get(&k[8..])? {
+                let mut e: Entry = deserialize(&v)?;
+                let d = e.date();
+                if d >= from && d <= to && matcher(&e).is_some() {
+                    e.meta = self.postprocess_meta(&e.meta)?;
+                    entries.push(e);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
     pub fn list_streams(&self) -> Result<Vec<Stream>> {
         let mut streams = self
-            .streams
-            .iter()
-            .map(|x| {
-                // let id = std::str::from_utf8(&x.clone().unwrap().0.to_owned());
-                deserialize(&x.clone().unwrap().1.to_owned()).unwrap()
-            })
+            .catalog
+            .read()
+            .unwrap()
+            .all()
+            .into_iter()
+            .cloned()
             .collect::<Vec<_>>();
 
-        streams.sort_by(|a: &Stream, b: &Stream| a.partial_cmp(&b).unwrap());
+        streams.sort_by(|a: &Stream, b: &Stream| a.partial_cmp(b).unwrap());
 
         Ok(streams)
     }
 
+    /// Mirrors the catalog's entry for `name` in the persisted `stream_names` tree.
+    pub(crate) fn index_stream_name(&self, catalog: &Catalog, name: &str) -> Result<()> {
+        match catalog.by_name(name) {
+            Some(s) => self.stream_names.insert(name.as_bytes(), s.id.as_bytes())?,
+            None => self.stream_names.remove(name.as_bytes())?,
+        };
+        Ok(())
+    }
+
+    /// Inserts or updates the stream `update`, failing if its name is used by another stream.
     pub fn insert_stream(&self, update: &Stream) -> Result<()> {
+        let mut catalog = self.catalog.write().unwrap();
+        if let Some(s) = catalog.by_name(&update.name) {
+            if s.id != update.id {
+                return Err(anyhow!("A stream named `{}` already exists", update.name));
+            }
+        }
         self.streams
             .insert(update.id.clone().as_bytes(), serialize(&update).unwrap())?;
+        if let Some(previous) = catalog.remove(&update.id) {
+            self.index_stream_name(&catalog, &previous.name)?;
+        }
+        catalog.insert(update.clone());
+        self.index_stream_name(&catalog, &update.name)?;
         Ok(())
     }
 
-    pub fn get_stream(&self, id: &String) -> Result<Option<Stream>> {
-        let s = &self.streams.get(id)?;
-        match s {
-            Some(d) => {
-                let stream: Stream = deserialize(d)?;
-                Ok(Some(stream))
+    /// Renames the stream `id` to `name` along with all the streams below it in the hierarchy
+    /// (renaming `Work` to `Job` moves `Work/ProjectX` to `Job/ProjectX`). Nothing is renamed if
+    /// one of the new names is already used by another stream. Returns the renamed streams.
+    pub fn rename_stream(&self, id: &str, name: &str) -> Result<Vec<Stream>> {
+        Ok(self.update_stream(id, name, None)?.1)
+    }
+
+    /// Renames the stream `id` to `name` as `rename_stream` does and replaces its `meta` with
+    /// `meta` if set, all in a single batch. Returns the stream once updated and the renamed
+    /// streams.
+    pub fn update_stream(
+        &self,
+        id: &str,
+        name: &str,
+        meta: Option<&str>,
+    ) -> Result<(Stream, Vec<Stream>)> {
+        validate_stream_name(name)?;
+
+        let mut catalog = self.catalog.write().unwrap();
+        let stream = match catalog.get(id) {
+            Some(s) => s.clone(),
+            None => return Err(anyhow!("Unknown stream: {}", id)),
+        };
+        let renaming = stream.name != name;
+        if renaming && stream.id == INBOX_ID {
+            return Err(anyhow!("The Inbox stream can't be renamed"));
+        }
+
+        let previous = match renaming {
+            true => std::iter::once(stream.clone())
+                .chain(catalog.descendants(&stream.name).into_iter().cloned())
+                .collect::<Vec<_>>(),
+            false => vec![stream.clone()],
+        };
+        let mut renamed = previous
+            .iter()
+            .map(|s| Stream {
+                name: format!("{}{}", name, &s.name[stream.name.len()..]),
+                ..s.clone()
+            })
+            .collect::<Vec<_>>();
+        if let Some(m) = meta {
+            renamed[0].meta = String::from(m);
+        }
+        if !renaming && renamed[0].meta == stream.meta {
+            return Ok((stream, vec![]));
+        }
+
+        for r in renamed.iter() {
+            if catalog
+                .all()
+                .iter()
+                .any(|o| o.name == r.name && !previous.contains(o))
+            {
+                return Err(anyhow!("A stream named `{}` already exists", r.name));
             }
+        }
+
+        let mut batch = sled::Batch::default();
+        for r in renamed.iter() {
+            batch.insert(r.id.as_bytes(), serialize(r)?);
+        }
+        self.streams.apply_batch(batch)?;
+
+        renamed.iter().for_each(|r| {
+            catalog.insert(r.clone());
+        });
+        for s in previous.iter().chain(renamed.iter()) {
+            self.index_stream_name(&catalog, &s.name)?;
+        }
+
+        let updated = renamed[0].clone();
+        Ok((updated, if renaming { renamed } else { vec![] }))
+    }
+
+    /// Returns the id of the stream named `name` from the persisted name index.
+    pub fn stream_id_by_name(&self, name: &str) -> Result<Option<String>> {
+        match self.stream_names.get(name)? {
+            Some(id) => Ok(Some(String::from_utf8(id.to_vec())?)),
             None => Ok(None),
         }
     }
 
+    pub fn get_stream(&self, id: &str) -> Result<Option<Stream>> {
+        Ok(self.catalog.read().unwrap().get(id).cloned())
+    }
+
     pub fn delete_stream(&self, id: &String) -> Result<()> {
-        let s = &self.streams.get(id)?;
-        let stream = match s {
-            Some(d) => {
-                let stream: Stream = deserialize(d)?;
-                Some(stream)
-            }
-            None => None,
+        let stream = match self.get_stream(id)? {
+            Some(s) => s,
+            None => return Ok(()),
         };
-        if !stream.is_some() {
-            return Ok(());
-        }
 
-        // Remove the stream from its entries.
-        let streams = vec![stream.unwrap()];
-        let all_entries: Vec<Entry> = self
-            .entries
-            .iter()
-            .rev()
-            .filter_map(|x| {
-                // let id = std::str::from_utf8(&x.clone().unwrap().0.to_owned());
-                let mut e: Entry = deserialize(&x.clone().unwrap().1.to_owned()).unwrap();
-                if self.match_meta(&streams, &e, false) {
-                    e.meta = e
-                        .meta
-                        .replace(format!("_stream_id_[{}]__", id.as_str()).as_str(), "");
-                    e.meta = String::from(e.meta.replacen("  ", " ", 2).trim());
-                    e.meta = self.postprocess_meta(&e.meta).unwrap();
-                    Some(e)
-                } else {
-                    None
+        // Remove the stream from its entries, quarantining the ones that can't be decoded.
+        let streams = vec![stream.clone()];
+        let mut all_entries: Vec<Entry> = vec![];
+        for x in self.entries.iter().rev() {
+            let (k, v) = x?;
+            let mut e: Entry = match deserialize(&v) {
+                Ok(e) => e,
+                Err(err) => {
+                    self.quarantine_record(&self.entries, &k, &v, &format!("{}", err))?;
+                    continue;
                 }
-            })
-            .collect::<Vec<_>>();
+            };
+            if self.match_meta(&streams, &e, false) {
+                e.meta = e
+                    .meta
+                    .replace(format!("_stream_id_[{}]__", id.as_str()).as_str(), "");
+                e.meta = String::from(e.meta.replacen("  ", " ", 2).trim());
+                e.meta = self.postprocess_meta(&e.meta)?;
+                all_entries.push(e);
+            }
+        }
 
         // And reinsert them.
-        all_entries.iter().for_each(|e| {
-            self.insert_entry(e).unwrap();
-        });
+        for e in all_entries.iter() {
+            self.insert_entry(e)?;
+        }
 
         self.streams.remove(id.as_bytes())?;
+        let mut catalog = self.catalog.write().unwrap();
+        catalog.remove(id);
+        self.index_stream_name(&catalog, &stream.name)?;
 
         Ok(())
     }
 }
+
+/// Opens a temporary `DB`, for tests.
+#[cfg(test)]
+pub(crate) fn test_db() -> DB {
+    DB::open(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+}
+
+/// Creates an entry in `db` now, for tests.
+#[cfg(test)]
+pub(crate) fn test_entry(db: &DB, meta: &str, title: &str, body: &str) -> Entry {
+    db.create_entry(&EntryCreation {
+        meta: String::from(meta),
+        title: String::from(title),
+        body: String::from(body),
+        created: None,
+        tz_offset: None,
+    })
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarantine_record() {
+        let db = test_db();
+        let e = test_entry(&db, "{Work} mood:3", "Foo", "- [ ] see [[Bar]]");
+        test_entry(&db, "", "Baz", "see [[Foo]]");
+        db.entries.insert(e.id.as_bytes(), vec![1, 2, 3]).unwrap();
+        db.quarantine_record(&db.entries, e.id.as_bytes(), &[1, 2, 3], "garbage")
+            .unwrap();
+
+        assert_eq!(1, db.quarantine.len());
+        assert_eq!(1, db.stats("").unwrap().entries);
+        assert_eq!(1, db.list_entries("", 0, 10).unwrap().0);
+        assert!(!db.is_known_field("mood").unwrap());
+        for tree in [&db.titles, &db.by_created, &db.by_updated, &db.tasks] {
+            assert!(tree
+                .iter()
+                .keys()
+                .all(|k| !String::from_utf8_lossy(&k.unwrap()).contains(&e.id)));
+        }
+        assert!(db.related_entries(&e.id, 5).is_err());
+        assert!(db
+            .links
+            .iter()
+            .keys()
+            .all(|k| !String::from_utf8_lossy(&k.unwrap()).contains(&e.id)));
+    }
+
+    #[test]
+    fn test_rename_stream() {
+        let db = test_db();
+        let e = test_entry(&db, "{Work/ProjectX/Design}", "Sketches", "");
+        let work = db.stream_by_name("Work", true).unwrap().unwrap();
+        let x = db.stream_by_name("Work/ProjectX", true).unwrap().unwrap();
+        db.stream_by_name("Home", true).unwrap();
+
+        assert!(db.rename_stream(&x.id, "Home").is_err());
+        assert!(db.rename_stream(&x.id, "Job/").is_err());
+        assert!(db.rename_stream(INBOX_ID, "Mail").is_err());
+        assert!(db
+            .insert_stream(&Stream {
+                id: generate_id(1),
+                name: String::from("Home"),
+                meta: String::from(""),
+            })
+            .is_err());
+
+        let mut r = db
+            .rename_stream(&work.id, "Job")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect::<Vec<_>>();
+        r.sort();
+        assert_eq!(vec!["Job", "Job/ProjectX", "Job/ProjectX/Design"], r);
+        assert!(db.stream_by_name("Work/ProjectX", false).unwrap().is_none());
+        assert_eq!(
+            x.id,
+            db.stream_by_name("Job/ProjectX", false)
+                .unwrap()
+                .unwrap()
+                .id
+        );
+        assert_eq!(
+            "{Job/ProjectX/Design}",
+            db.get_entry(&e.id).unwrap().unwrap().meta
+        );
+        assert_eq!(1, db.list_entries("{Job}", 0, 10).unwrap().0);
+
+        // Attributes are kept unless given, and written along with the name.
+        let (s, _) = db
+            .update_stream(&x.id, "Job/X", Some("{\"pinned\":true}"))
+            .unwrap();
+        assert_eq!("{\"pinned\":true}", s.meta);
+        let (s, r) = db.update_stream(&x.id, "Job/Y", None).unwrap();
+        assert_eq!(
+            ("Job/Y", "{\"pinned\":true}"),
+            (s.name.as_str(), s.meta.as_str())
+        );
+        assert_eq!(2, r.len());
+        assert_eq!(s.meta, db.get_stream(&x.id).unwrap().unwrap().meta);
+        assert!(db.update_stream(&x.id, "Home", Some("")).is_err());
+        assert_eq!(s.meta, db.get_stream(&x.id).unwrap().unwrap().meta);
+    }
+}
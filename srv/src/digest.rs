@@ -0,0 +1,229 @@
+use crate::db::{extract_stream_names, DB};
+use crate::models::{Entry, EntryCreation};
+use crate::stats::count_words;
+use crate::time::{self, Bucket};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// A digest summarizes the entries of a period matching a query as a Markdown document: entries are
+// grouped by their first stream, then by day, with their title and the first line of their body.
+// Saved digests carry a `digest:<period>` field and are left out of later digests.
+
+/// Maximum length (in characters) of the first line of an entry in a digest.
+const FIRST_LINE_LENGTH: usize = 120;
+
+/// Heading of the entries without stream.
+const NO_STREAM: &str = "No stream";
+
+/// Request for the digest of the entries matching `query` during `period`, a day, week or month as
+/// written in queries (`last-week`, `this-month`, `2026-W43`, `2026-10`, ..., see `Bucket::parse`).
+/// If `save` is set, the digest is also saved as a new entry of that stream.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DigestRequest {
+    #[serde(default)]
+    pub query: String,
+    pub period: String,
+    #[serde(default)]
+    pub save: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Digest {
+    /// Label of the period, see `Bucket::label`.
+    pub period: String,
+    pub from: String,
+    pub to: String,
+    pub entries: u64,
+    pub words: u64,
+    /// Number of days with entries.
+    pub days: u64,
+    pub title: String,
+    pub markdown: String,
+    /// The entry the digest was saved as, if requested.
+    pub entry: Option<Entry>,
+}
+
+/// Returns the first non-empty line of `body`, shortened to `FIRST_LINE_LENGTH` characters.
+fn first_line(body: &str) -> String {
+    let line = body
+        .lines()
+        .map(|l| l.trim().trim_start_matches('#').trim())
+        .find(|l| !l.is_empty())
+        .unwrap_or("");
+    if line.chars().count() > FIRST_LINE_LENGTH {
+        let short = line.chars().take(FIRST_LINE_LENGTH).collect::<String>();
+        format!("{}…", short.trim_end())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Renders the body of the digest of `entries` (with name-based `meta`), see `Digest`.
+fn render(entries: &[Entry], from: NaiveDate, to: NaiveDate) -> (String, u64, u64) {
+    let mut groups: BTreeMap<String, BTreeMap<NaiveDate, Vec<&Entry>>> = BTreeMap::new();
+    let mut totals: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    let mut days: BTreeSet<NaiveDate> = BTreeSet::new();
+    let mut words = 0;
+    for e in entries.iter() {
+        let stream = extract_stream_names(&e.meta)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| String::from(NO_STREAM));
+        let w = count_words(e);
+        words += w;
+        days.insert(e.date());
+        let t = totals.entry(stream.clone()).or_default();
+        t.0 += 1;
+        t.1 += w;
+        groups
+            .entry(stream)
+            .or_default()
+            .entry(e.date())
+            .or_default()
+            .push(e);
+    }
+
+    let mut out = format!(
+        "{} to {}: {} entries, {} words over {} days.\n",
+        Bucket::Day.label(from),
+        Bucket::Day.label(to),
+        entries.len(),
+        words,
+        days.len()
+    );
+    for (stream, by_day) in groups.iter() {
+        out.push_str(&format!("\n## {}\n", stream));
+        for (d, entries) in by_day.iter() {
+            out.push_str(&format!("\n### {}\n\n", d.format("%A %Y-%m-%d")));
+            for e in entries.iter() {
+                let line = first_line(&e.body);
+                let item = match (e.title.trim(), line.as_str()) {
+                    ("", "") => String::from("(empty)"),
+                    ("", l) => l.to_string(),
+                    (t, "") => format!("**{}**", t),
+                    (t, l) => format!("**{}**: {}", t, l),
+                };
+                out.push_str(&format!("- {}\n", item));
+            }
+        }
+    }
+
+    if !totals.is_empty() {
+        out.push_str("\n## Statistics\n\n| Stream | Entries | Words |\n|---|---|---|\n");
+        for (stream, (entries, words)) in totals.iter() {
+            out.push_str(&format!("| {} | {} | {} |\n", stream, entries, words));
+        }
+    }
+    (out, words, days.len() as u64)
+}
+
+impl DB {
+    /// Builds the digest of the entries matching `query` during `period` (see `DigestRequest`),
+    /// saving it as a new entry of the stream `save` if set.
+    pub fn digest(&self, query: &str, period: &str, save: Option<&str>) -> Result<Digest> {
+        let (bucket, from) = match Bucket::parse(period, time::today()) {
+            Some(p) => p,
+            None => return Err(anyhow!("Invalid period: {}", period)),
+        };
+        let to = match bucket.next(from) {
+            Some(next) => next - Duration::days(1),
+            None => return Err(anyhow!("Invalid period: {}", period)),
+        };
+        let label = bucket.label(from);
+
+        let entries = self
+            .entries_between(query, from, to)?
+            .into_iter()
+            .filter(|e| !e.fields.contains_key("digest"))
+            .collect::<Vec<_>>();
+        let (body, words, days) = render(&entries, from, to);
+        let title = format!("Digest {}", label);
+
+        let entry = match save {
+            Some(stream) => Some(self.create_entry(&EntryCreation {
+                meta: format!("{{{}}} digest:{}", stream, label),
+                title: title.clone(),
+                body: body.clone(),
+                created: None,
+                tz_offset: None,
+            })?),
+            None => None,
+        };
+
+        Ok(Digest {
+            period: label,
+            from: Bucket::Day.label(from),
+            to: Bucket::Day.label(to),
+            entries: entries.len() as u64,
+            words,
+            days,
+            markdown: format!("# {}\n\n{}", title, body),
+            title,
+            entry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!("Plan", first_line("\n## Plan \nmore"));
+        assert_eq!(
+            format!("{}…", "a".repeat(FIRST_LINE_LENGTH)),
+            first_line(&"a".repeat(200))
+        );
+
+        let db = test_db();
+        let today = time::today();
+        let week = Bucket::Week.previous(today);
+        let created = time::day_start(week, time::local_offset(time::day_start(week, 0))) + 3600;
+        let entry = |id: &str, days: u64, meta: &str, title: &str, body: &str| {
+            db.insert_entry(&Entry {
+                id: String::from(id),
+                created: created + days * 86400,
+                meta: String::from(meta),
+                title: String::from(title),
+                body: String::from(body),
+                ..Entry::default()
+            })
+            .unwrap();
+        };
+        entry("1-a", 0, "{Work/A}", "Kickoff", "Met the team.\nMore");
+        entry("1-b", 2, "{Work}", "", "Shipped it");
+        entry("1-c", 2, "{Home}", "Garden", "");
+        entry("1-d", 7, "{Work}", "Next week", "");
+
+        let d = db.digest("{Work}", "last-week", None).unwrap();
+        assert_eq!(Bucket::Week.label(week), d.period);
+        assert_eq!((2, 7, 2), (d.entries, d.words, d.days));
+        assert!(d.markdown.starts_with(&format!("# Digest {}\n", d.period)));
+        assert!(d.markdown.contains("## Work/A\n"));
+        assert!(d.markdown.contains("- **Kickoff**: Met the team.\n"));
+        assert!(d.markdown.contains("- Shipped it\n"));
+        assert!(d.markdown.contains("| Work | 1 | 2 |\n"));
+        assert!(!d.markdown.contains("Garden"));
+        assert!(d.entry.is_none());
+
+        // Saved digests are entries of their own, left out of later digests.
+        let d = db.digest("", "last-week", Some("Digests")).unwrap();
+        assert_eq!(3, d.entries);
+        let e = d.entry.unwrap();
+        assert_eq!(format!("{{Digests}} digest:{}", d.period), e.meta);
+        assert_eq!(d.title, e.title);
+        assert_eq!(
+            0,
+            db.digest("{Digests}", "this-week", None).unwrap().entries
+        );
+
+        assert!(db.digest("", "soon", None).is_err());
+        assert!(db
+            .digest("", &Bucket::Month.label(NaiveDate::MAX), None)
+            .is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::db::DB;
+use crate::models::{Entry, EntryPrev, EntryV2, EntryV4, EntryV6, Quarantined, Stream, StreamPrev};
+use anyhow::{anyhow, Result};
+use bincode::deserialize;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DoctorAction {
+    List,
+    Inspect,
+    Repair,
+    Discard,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DoctorRequest {
+    pub action: DoctorAction,
+    /// Key of the record in the `quarantine` tree (`<tree>/<key>`), required for all actions
+    /// but `list`.
+    #[serde(default)]
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QuarantinedSummary {
+    pub key: String,
+    pub tree: String,
+    pub record_key: String,
+    pub error: String,
+    pub quarantined: u64,
+    pub size: usize,
+}
+
+/// A quarantined record with a hex dump of its raw bytes and, if it could be decoded as a
+/// current or legacy record, its JSON representation.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Inspection {
+    pub summary: QuarantinedSummary,
+    pub hex: String,
+    pub decoded: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DoctorResponse {
+    pub quarantined: Vec<QuarantinedSummary>,
+    pub inspection: Option<Inspection>,
+}
+
+/// A record recovered from quarantine, ready to be restored in its tree.
+#[derive(Debug, Clone)]
+pub enum Recovered {
+    Entry(Entry),
+    Stream(Stream),
+}
+
+/// Formats `data` as a classic hex dump: offset, 16 bytes in hex and their printable ASCII.
+pub fn hexdump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, c)| {
+            let hex = c
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = c
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() || *b == b' ' {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            format!("{:08x}  {:<47}  |{}|", i * 16, hex, ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Tries to decode a quarantined record as a current record, then as a legacy one. Legacy
+/// entries missing their id get the key they were stored at. Their creation time isn't read from
+/// their id, which is opaque (see `generate_id`).
+pub fn recover(q: &Quarantined) -> Result<Recovered> {
+    match q.tree.as_str() {
+        "entries" => {
+            if let Ok(e) = deserialize::<Entry>(&q.data) {
+                return Ok(Recovered::Entry(e));
+            }
+            if let Ok(e) = deserialize::<EntryV6>(&q.data) {
+                return Ok(Recovered::Entry(e.into()));
+            }
+            if let Ok(e) = deserialize::<EntryV4>(&q.data) {
+                return Ok(Recovered::Entry(e.into()));
+            }
+            if let Ok(e) = deserialize::<EntryV2>(&q.data) {
+                return Ok(Recovered::Entry(e.into()));
+            }
+            let p: EntryPrev = deserialize(&q.data)?;
+            let id = p.id.unwrap_or_else(|| q.key.clone());
+            let created = p
+                .created
+                .ok_or_else(|| anyhow!("Unable to recover `created` of entry: {}", id))?;
+            Ok(Recovered::Entry(
+                EntryV2 {
+                    id,
+                    created,
+                    meta: p.meta,
+                    title: p.title,
+                    body: p.body,
+                }
+                .into(),
+            ))
+        }
+        "streams" => {
+            if let Ok(s) = deserialize::<Stream>(&q.data) {
+                return Ok(Recovered::Stream(s));
+            }
+            let p: StreamPrev = deserialize(&q.data)?;
+            Ok(Recovered::Stream(Stream {
+                id: p.id,
+                meta: String::from(""),
+                name: p.name,
+            }))
+        }
+        t => Err(anyhow!("Unknown tree: {}", t)),
+    }
+}
+
+fn summary(key: &[u8], q: &Quarantined) -> QuarantinedSummary {
+    QuarantinedSummary {
+        key: String::from_utf8_lossy(key).to_string(),
+        tree: q.tree.clone(),
+        record_key: q.key.clone(),
+        error: q.error.clone(),
+        quarantined: q.quarantined,
+        size: q.data.len(),
+    }
+}
+
+impl DB {
+    fn get_quarantined(&self, key: &str) -> Result<Quarantined> {
+        match self.quarantine.get(key)? {
+            Some(v) => Ok(deserialize(&v)?),
+            None => Err(anyhow!("Unknown quarantined record: {}", key)),
+        }
+    }
+
+    pub fn list_quarantined(&self) -> Result<Vec<QuarantinedSummary>> {
+        self.quarantine
+            .iter()
+            .map(|x| {
+                let (k, v) = x?;
+                Ok(summary(&k, &deserialize(&v)?))
+            })
+            .collect()
+    }
+
+    pub fn inspect_quarantined(&self, key: &str) -> Result<Inspection> {
+        let q = self.get_quarantined(key)?;
+        let decoded = match recover(&q) {
+            Ok(Recovered::Entry(e)) => Some(serde_json::to_string_pretty(&e)?),
+            Ok(Recovered::Stream(s)) => Some(serde_json::to_string_pretty(&s)?),
+            Err(_) => None,
+        };
+        Ok(Inspection {
+            summary: summary(key.as_bytes(), &q),
+            hex: hexdump(&q.data),
+            decoded,
+        })
+    }
+
+    /// Restores a quarantined record in its tree if it can be decoded (see `recover`). Existing
+    /// records are never overwritten. Entries go through `insert_entry` so that legacy name-based
+    /// meta is resolved and their fields and UTC offset are set.
+    pub fn repair_quarantined(&self, key: &str) -> Result<()> {
+        let q = self.get_quarantined(key)?;
+        match recover(&q)? {
+            Recovered::Entry(e) => {
+                if self.entries.contains_key(&e.id)? {
+                    return Err(anyhow!("A record already exists with id: {}", e.id));
+                }
+                self.insert_entry(&e)?;
+            }
+            Recovered::Stream(s) => {
+                if self.streams.contains_key(&s.id)? {
+                    return Err(anyhow!("A record already exists with id: {}", s.id));
+                }
+                self.insert_stream(&s)?;
+            }
+        }
+        self.quarantine.remove(key)?;
+        Ok(())
+    }
+
+    pub fn discard_quarantined(&self, key: &str) -> Result<()> {
+        self.get_quarantined(key)?;
+        self.quarantine.remove(key)?;
+        Ok(())
+    }
+
+    pub fn doctor(&self, request: &DoctorRequest) -> Result<DoctorResponse> {
+        let inspection = match request.action {
+            DoctorAction::List => None,
+            DoctorAction::Inspect => Some(self.inspect_quarantined(&request.key)?),
+            DoctorAction::Repair => {
+                self.repair_quarantined(&request.key)?;
+                None
+            }
+            DoctorAction::Discard => {
+                self.discard_quarantined(&request.key)?;
+                None
+            }
+        };
+        Ok(DoctorResponse {
+            quarantined: self.list_quarantined()?,
+            inspection,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::quarantine;
+    use crate::db::test_db;
+    use bincode::serialize;
+
+    #[test]
+    fn test_doctor() {
+        assert_eq!(
+            "00000000  66 6f 6f 00                                      |foo.|",
+            hexdump(b"foo\0")
+        );
+
+        let db = test_db();
+        let e = EntryPrev {
+            id: None,
+            created: Some(1626000100),
+            meta: String::from("{Work} mood:3"),
+            title: String::from("Foo"),
+            body: String::from("Bar"),
+        };
+        quarantine(
+            &db.quarantine,
+            &db.entries,
+            b"1626000000-foo",
+            &serialize(&e).unwrap(),
+            "legacy",
+        )
+        .unwrap();
+        quarantine(&db.quarantine, &db.streams, b"bar", &[1, 2, 3], "garbage").unwrap();
+
+        let l = db.list_quarantined().unwrap();
+        assert_eq!(
+            vec!["entries/1626000000-foo", "streams/bar"],
+            l.iter().map(|q| q.key.as_str()).collect::<Vec<_>>()
+        );
+
+        let i = db.inspect_quarantined("entries/1626000000-foo").unwrap();
+        assert!(i.decoded.unwrap().contains("\"created\": 1626000100"));
+        assert!(db
+            .inspect_quarantined("streams/bar")
+            .unwrap()
+            .decoded
+            .is_none());
+
+        assert!(db.repair_quarantined("streams/bar").is_err());
+        db.repair_quarantined("entries/1626000000-foo").unwrap();
+        let e = db
+            .get_entry(&String::from("1626000000-foo"))
+            .unwrap()
+            .unwrap();
+        assert_eq!("Bar", e.body);
+        assert!(e.fields.contains_key("mood") && e.tz_offset.is_some());
+        assert_eq!(1626000100, e.created);
+        assert_eq!(1, db.list_entries("{Work}", 0, 10).unwrap().0);
+
+        let r = db
+            .doctor(&DoctorRequest {
+                action: DoctorAction::Discard,
+                key: String::from("streams/bar"),
+            })
+            .unwrap();
+        assert!(r.quarantined.is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::models::Entry;
+use chrono::{DateTime, SecondsFormat};
+use pulldown_cmark::{html, Options, Parser};
+
+/// Escapes `s` for inclusion in HTML or XML text and attributes.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Renders Markdown to HTML.
+pub fn render_markdown(s: &str) -> String {
+    let mut out = String::new();
+    html::push_html(
+        &mut out,
+        Parser::new_ext(s, Options::ENABLE_TABLES | Options::ENABLE_TASKLISTS),
+    );
+    out
+}
+
+/// Formats an epoch timestamp in seconds as RFC 3339, as expected by Atom.
+pub fn rfc3339(secs: u64) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Last update time of `entry`, falling back to its creation time for entries not updated since
+/// `updated` was introduced.
+fn updated(entry: &Entry) -> u64 {
+    entry.updated.max(entry.created)
+}
+
+/// Describes an Atom feed. `link` is the URL of the feed itself, `author` the name given as the
+/// author of all its entries (Atom requires one) and `entry_link` returns the permalink of an
+/// entry, if entries have one.
+pub struct Feed<'a> {
+    pub id: String,
+    pub title: String,
+    pub author: String,
+    pub link: String,
+    pub entry_link: Option<&'a dyn Fn(&Entry) -> String>,
+}
+
+impl<'a> Feed<'a> {
+    /// Renders the Atom feed of `entries`, expected most recent first.
+    pub fn render(&self, entries: &[Entry]) -> String {
+        let last_updated = entries.iter().map(updated).max().unwrap_or(0);
+
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        out.push_str(&format!("  <id>{}</id>\n", escape(&self.id)));
+        out.push_str(&format!("  <title>{}</title>\n", escape(&self.title)));
+        out.push_str(&format!("  <updated>{}</updated>\n", rfc3339(last_updated)));
+        out.push_str(&format!(
+            "  <author><name>{}</name></author>\n",
+            escape(&self.author)
+        ));
+        out.push_str(&format!(
+            "  <link rel=\"self\" href=\"{}\"/>\n",
+            escape(&self.link)
+        ));
+        for e in entries {
+            out.push_str("  <entry>\n");
+            out.push_str(&format!("    <id>urn:dump:entry:{}</id>\n", escape(&e.id)));
+            out.push_str(&format!("    <title>{}</title>\n", escape(&e.title)));
+            out.push_str(&format!(
+                "    <published>{}</published>\n",
+                rfc3339(e.created)
+            ));
+            out.push_str(&format!("    <updated>{}</updated>\n", rfc3339(updated(e))));
+            if let Some(entry_link) = self.entry_link {
+                out.push_str(&format!(
+                    "    <link rel=\"alternate\" href=\"{}\"/>\n",
+                    escape(&entry_link(e))
+                ));
+            }
+            out.push_str(&format!(
+                "    <content type=\"html\">{}</content>\n",
+                escape(&render_markdown(&e.body))
+            ));
+            out.push_str("  </entry>\n");
+        }
+        out.push_str("</feed>\n");
+        out
+    }
+}
@@ -0,0 +1,188 @@
+use crate::db::DB;
+use crate::models::{Entry, Field};
+use anyhow::Result;
+use chrono::{Duration, NaiveDate};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Parses the raw value of a field (`4`, `"Paris"`, `2026-11-01`, `today`, `@alice`). Relative
+/// dates are resolved against `today`.
+pub fn parse_value(raw: &str, today: NaiveDate) -> Field {
+    if raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"') {
+        return Field::Text(raw[1..raw.len() - 1].to_string());
+    }
+    if let Some(p) = raw.strip_prefix('@') {
+        if !p.is_empty() {
+            return Field::Person(p.to_string());
+        }
+    }
+    let date = match raw {
+        "today" => Some(today),
+        "yesterday" => Some(today - Duration::days(1)),
+        "tomorrow" => Some(today + Duration::days(1)),
+        _ => NaiveDate::parse_from_str(raw, "%Y-%m-%d").ok(),
+    };
+    if let Some(d) = date {
+        return Field::Date(d.format("%Y-%m-%d").to_string());
+    }
+    match raw.parse::<f64>() {
+        Ok(n) if n.is_finite() => Field::Number(n),
+        _ => Field::Text(raw.to_string()),
+    }
+}
+
+impl Field {
+    /// Normalized form of the value, as stored in `meta`. Text is quoted when it would otherwise
+    /// be read as another type or contains spaces.
+    pub fn to_token(&self) -> String {
+        match self {
+            Field::Number(n) => format!("{}", n),
+            Field::Date(d) => d.clone(),
+            Field::Person(p) => format!("@{}", p),
+            Field::Text(t) => {
+                let bare = !t.is_empty()
+                    && !t.contains(|c: char| c.is_whitespace() || "\"{}".contains(c))
+                    && !t.starts_with('/')
+                    && parse_value(t, NaiveDate::MIN) == *self;
+                if bare {
+                    t.clone()
+                } else {
+                    format!("\"{}\"", t.replace('"', ""))
+                }
+            }
+        }
+    }
+
+    /// Compares two values of the same type. Text and persons are compared case-insensitively;
+    /// numbers and dates can't be compared to other types.
+    pub fn compare(&self, other: &Field) -> Option<Ordering> {
+        match (self, other) {
+            (Field::Number(a), Field::Number(b)) => a.partial_cmp(b),
+            (Field::Date(a), Field::Date(b)) => Some(a.cmp(b)),
+            (Field::Number(_), _) | (_, Field::Number(_)) => None,
+            (Field::Date(_), _) | (_, Field::Date(_)) => None,
+            (Field::Person(a), Field::Person(b))
+            | (Field::Person(a), Field::Text(b))
+            | (Field::Text(a), Field::Person(b))
+            | (Field::Text(a), Field::Text(b)) => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+        }
+    }
+}
+
+lazy_static! {
+    /// Matches stream names (left untouched) or `key:value` fields, where the value is either
+    /// quoted or a single word (not starting with `/` so that URLs aren't mistaken for fields).
+    static ref FIELD_RE: Regex = Regex::new(
+        r#"\{[^\{\}]*\}|(?P<pre>^|\s)(?P<key>[a-z][a-z0-9_]*):(?P<value>"[^"]*"|[^\s"\{\}/][^\s"\{\}]*)"#
+    )
+    .unwrap();
+}
+
+/// Extracts the `key:value` fields of `meta` and returns `meta` with the fields in their
+/// normalized form along with the fields. Relative dates are resolved against `today`, the date
+/// of the entry (see `Entry::date`). If a key appears multiple times the last value wins.
+pub fn parse(meta: &str, today: NaiveDate) -> (String, BTreeMap<String, Field>) {
+    let mut fields = BTreeMap::new();
+    let normalized = FIELD_RE.replace_all(meta, |c: &Captures| match c.name("key") {
+        Some(key) => {
+            let value = parse_value(&c["value"], today);
+            let token = format!("{}{}:{}", &c["pre"], key.as_str(), value.to_token());
+            fields.insert(key.as_str().to_string(), value);
+            token
+        }
+        None => c[0].to_string(),
+    });
+    (normalized.to_string(), fields)
+}
+
+// The `fields` tree records which entries have which fields, with `<key>\0<entry id>` keys, so
+// that queries only treat `key:value` tokens as filters for known keys (see `query::parse`).
+
+fn field_key(key: &str, id: &str) -> Vec<u8> {
+    format!("{}\0{}", key, id).into_bytes()
+}
+
+impl DB {
+    /// Updates the `fields` index for `entry`, replacing `previous`.
+    pub(crate) fn index_fields(&self, entry: &Entry, previous: Option<&Entry>) -> Result<()> {
+        if let Some(p) = previous {
+            for k in p.fields.keys().filter(|k| !entry.fields.contains_key(*k)) {
+                self.fields.remove(field_key(k, &p.id))?;
+            }
+        }
+        for k in entry.fields.keys() {
+            self.fields.insert(field_key(k, &entry.id), &[])?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unindex_fields(&self, entry: &Entry) -> Result<()> {
+        for k in entry.fields.keys() {
+            self.fields.remove(field_key(k, &entry.id))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of the entries with the field `key`.
+    pub(crate) fn entries_with_field(&self, key: &str) -> Result<Vec<String>> {
+        let mut prefix = key.as_bytes().to_vec();
+        prefix.push(0);
+        self.fields
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|k| Ok(String::from_utf8_lossy(&k?[prefix.len()..]).to_string()))
+            .collect()
+    }
+
+    /// Whether some entry has the field `key`.
+    pub fn is_known_field(&self, key: &str) -> Result<bool> {
+        let mut prefix = key.as_bytes().to_vec();
+        prefix.push(0);
+        Ok(self
+            .fields
+            .scan_prefix(prefix)
+            .next()
+            .transpose()?
+            .is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fields() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let (meta, fields) = parse(
+            "{Work/Notes: a:b} mood:4.0 location:\"Paris\" status:\"done\" due:tomorrow \
+             people:@alice see http://foo.com/a:b code:\"42\"",
+            today,
+        );
+        assert_eq!(
+            "{Work/Notes: a:b} mood:4 location:Paris status:done due:2026-10-20 \
+             people:@alice see http://foo.com/a:b code:\"42\"",
+            meta
+        );
+        assert_eq!(Some(&Field::Number(4.0)), fields.get("mood"));
+        assert_eq!(
+            Some(&Field::Text(String::from("Paris"))),
+            fields.get("location")
+        );
+        assert_eq!(
+            Some(&Field::Date(String::from("2026-10-20"))),
+            fields.get("due")
+        );
+        assert_eq!(
+            Some(&Field::Person(String::from("alice"))),
+            fields.get("people")
+        );
+        assert_eq!(Some(&Field::Text(String::from("42"))), fields.get("code"));
+        assert_eq!(6, fields.len());
+
+        // Normalization is idempotent.
+        assert_eq!(meta, parse(&meta, today).0);
+    }
+}
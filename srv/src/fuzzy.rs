@@ -0,0 +1,193 @@
+use crate::db::DB;
+use crate::models::Stream;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Fuzzy matching tolerates typos: words match within an edit distance (insertions, deletions,
+// substitutions and transpositions of adjacent characters) bounded by their length, see
+// `max_distance`. It is selected with a `~` suffix in queries (`meetng~`) or `ListOptions.fuzzy`.
+
+/// Request for the streams whose name completes `name`, closest first.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StreamSearch {
+    pub name: String,
+    #[serde(default)]
+    pub fuzzy: bool,
+    #[serde(default)]
+    pub archived: bool,
+}
+
+/// Maximum edit distance tolerated for a word of `len` characters.
+pub fn max_distance(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=6 => 1,
+        _ => 2,
+    }
+}
+
+/// Edit distance between `a` and `b`, transpositions of adjacent characters counting as one edit
+/// (optimal string alignment).
+pub fn distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Lowercased words of `text`.
+pub fn tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Returns the sum over the words of `query` of their distance to the closest word of `text` (or
+/// prefix of it, see `prefix_distance`), or `None` if one of them matches no word. Words contained
+/// in a word of `text` match exactly.
+pub fn score(query: &str, text: &str) -> Option<usize> {
+    let words = tokens(text);
+    let mut total = 0;
+    for q in tokens(query) {
+        total += words
+            .iter()
+            .filter_map(|w| {
+                if w.contains(&q) {
+                    Some(0)
+                } else {
+                    prefix_distance(&q, w)
+                }
+            })
+            .min()?;
+    }
+    Some(total)
+}
+
+/// Returns the distance of `input` to the closest prefix of `name` (case insensitive), or `None`
+/// if it is above `max_distance`.
+pub fn prefix_distance(input: &str, name: &str) -> Option<usize> {
+    let input = input.to_lowercase();
+    let name = name.to_lowercase().chars().collect::<Vec<_>>();
+    let len = input.chars().count();
+    let max = max_distance(len);
+    (len.saturating_sub(max)..=(len + max).min(name.len()))
+        .map(|l| distance(&input, &name[..l].iter().collect::<String>()))
+        .filter(|d| *d <= max)
+        .min()
+}
+
+impl DB {
+    /// Returns the streams whose name starts with `name` (case insensitive), or with a prefix close
+    /// to it if `fuzzy` (see `prefix_distance`), closest first.
+    pub fn search_streams(&self, name: &str, fuzzy: bool) -> Result<Vec<Stream>> {
+        let lower = name.to_lowercase();
+        let mut found = self
+            .list_streams()?
+            .into_iter()
+            .filter_map(|s| {
+                let d = if s.name.to_lowercase().starts_with(&lower) {
+                    Some(0)
+                } else if fuzzy {
+                    prefix_distance(name, &s.name)
+                } else {
+                    None
+                };
+                d.map(|d| (d, s))
+            })
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then(a.1.name.len().cmp(&b.1.name.len()))
+                .then(a.1.name.cmp(&b.1.name))
+        });
+        Ok(found.into_iter().map(|(_, s)| s).collect())
+    }
+
+    /// Finds a stream by name, or else the stream whose name is the closest to `name` within
+    /// `max_distance`.
+    pub fn stream_by_name_fuzzy(&self, name: &str) -> Result<Option<Stream>> {
+        if let Some(s) = self.stream_by_name(name, false)? {
+            return Ok(Some(s));
+        }
+        let lower = name.to_lowercase();
+        let max = max_distance(lower.chars().count());
+        Ok(self
+            .list_streams()?
+            .into_iter()
+            .map(|s| (distance(&lower, &s.name.to_lowercase()), s))
+            .filter(|(d, _)| *d <= max)
+            .min_by(|a, b| a.0.cmp(&b.0).then(a.1.name.cmp(&b.1.name)))
+            .map(|(_, s)| s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_fuzzy() {
+        assert_eq!(1, distance("meetng", "meeting"));
+        assert_eq!(1, distance("wrok", "work"));
+        assert_eq!(3, distance("kitten", "sitting"));
+        assert_eq!(Some(1), score("meetng", "Team meeting notes"));
+        assert_eq!(Some(1), score("meetng", "Meetings"));
+        assert_eq!(Some(0), score("meet", "Team meeting notes"));
+        assert_eq!(None, score("meetng notez", "Team meeting"));
+        assert_eq!(None, score("cat", "car"));
+        assert_eq!(Some(1), prefix_distance("Wrok", "Work/Meetings"));
+        assert_eq!(None, prefix_distance("Hmoe", "Work"));
+
+        let db = test_db();
+        let entry = |meta: &str, title: &str| test_entry(&db, meta, title, "");
+        let a = entry("{Work}", "Weekly meeting");
+        let b = entry("{Work/Meetings}", "Meetng recap");
+        entry("{Home}", "Groceries");
+
+        assert_eq!(1, db.list_entries("meetng", 0, 10).unwrap().0);
+        let (total, entries) = db.list_entries("meetng~", 0, 10).unwrap();
+        assert_eq!(2, total);
+        assert_eq!(b.id, entries[0].id);
+        assert_eq!(a.id, entries[1].id);
+        assert_eq!(2, db.list_entries("{Wrok}~", 0, 10).unwrap().0);
+        assert_eq!(
+            1,
+            db.list_entries("{Wrok/Meetings} recap~", 0, 10).unwrap().0
+        );
+
+        assert_eq!(
+            vec!["Work", "Work/Meetings"],
+            db.search_streams("wor", false)
+                .unwrap()
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(db.search_streams("Wrok", false).unwrap().is_empty());
+        assert_eq!(2, db.search_streams("Wrok", true).unwrap().len());
+        assert_eq!(
+            "Home",
+            db.stream_by_name_fuzzy("hoem").unwrap().unwrap().name
+        );
+        assert!(db.stream_by_name_fuzzy("Garden").unwrap().is_none());
+    }
+}
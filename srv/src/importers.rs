@@ -0,0 +1,521 @@
+use crate::db::{generate_id, validate_stream_name, DB};
+use crate::markdown::{list_files, ImportAction, ImportError, ImportFile, ImportReport};
+use crate::models::{Entry, Field};
+use crate::time;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Day One JSON export, one `<Journal>.json` file per journal.
+    DayOne,
+    /// Journey or Diarium JSON export, an entry or an array of entries per file.
+    Journey,
+    /// Obsidian or Logseq daily notes folder (`2021-03-01.md` or `2021_03_01.md`).
+    DailyNotes,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImporterOptions {
+    pub format: Format,
+    pub path: String,
+    /// Additional meta added to every imported entry (eg: `{Imported}`).
+    #[serde(default)]
+    pub meta: String,
+    /// UTC offset (in seconds east of UTC) at which the dates of the export without time zone are
+    /// read, the local time zone if not set.
+    #[serde(default)]
+    pub tz_offset: Option<i32>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// An entry as read from another journaling app, before its conversion to an `Entry`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Imported {
+    /// Id of the entry in the app it comes from (prefixed with the app, eg: `dayone:<uuid>`), if
+    /// any. It is stored in the `source` field of the entry so that it is skipped on later imports.
+    pub source_id: Option<String>,
+    pub created: u64,
+    pub tz_offset: i32,
+    pub streams: Vec<String>,
+    pub title: String,
+    pub body: String,
+}
+
+/// Reads the naive date-time `d` at the UTC offset `tz_offset`, or in the local time zone if not
+/// set. Returns its timestamp and UTC offset.
+fn at_offset(d: NaiveDateTime, tz_offset: Option<i32>) -> Result<(u64, i32)> {
+    let t = match tz_offset {
+        Some(o) => FixedOffset::east_opt(o)
+            .ok_or_else(|| anyhow!("Invalid UTC offset: {}", o))?
+            .from_local_datetime(&d)
+            .earliest(),
+        None => Local
+            .from_local_datetime(&d)
+            .earliest()
+            .map(|t| t.fixed_offset()),
+    };
+    match t {
+        Some(t) => Ok((t.timestamp().max(0) as u64, t.offset().local_minus_utc())),
+        None => Err(anyhow!("Invalid date: {}", d)),
+    }
+}
+
+/// Parses a date as found in journaling apps exports: RFC 3339, naive date-times and dates (read
+/// at `tz_offset`, see `at_offset`), or epoch timestamps in seconds or milliseconds. Returns its
+/// timestamp and the UTC offset of its author: the one of the date if it has one other than `Z`,
+/// which only tells the date is in UTC, or else `tz_offset` (the local one if not set).
+pub fn parse_timestamp(v: &Value, tz_offset: Option<i32>) -> Result<(u64, i32)> {
+    match v {
+        Value::Number(n) => {
+            let n = n
+                .as_u64()
+                .ok_or_else(|| anyhow!("Invalid timestamp: {}", n))?;
+            // Timestamps after 5138 in seconds are most certainly in milliseconds.
+            let secs = if n > 100_000_000_000 { n / 1000 } else { n };
+            Ok((secs, tz_offset.unwrap_or_else(|| time::local_offset(secs))))
+        }
+        Value::String(s) => {
+            if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+                let secs = d.timestamp().max(0) as u64;
+                let offset = if s.ends_with('Z') || s.ends_with('z') {
+                    tz_offset.unwrap_or_else(|| time::local_offset(secs))
+                } else {
+                    d.offset().local_minus_utc()
+                };
+                return Ok((secs, offset));
+            }
+            for f in [
+                "%Y-%m-%dT%H:%M:%S",
+                "%Y-%m-%d %H:%M:%S",
+                "%Y-%m-%dT%H:%M:%S%.f",
+            ] {
+                if let Ok(d) = NaiveDateTime::parse_from_str(s, f) {
+                    return at_offset(d, tz_offset);
+                }
+            }
+            parse_date(s, tz_offset)
+        }
+        _ => Err(anyhow!("Invalid timestamp: {}", v)),
+    }
+}
+
+/// Parses a `2021-03-01` or `2021_03_01` date as midnight at `tz_offset`, see `at_offset`.
+fn parse_date(s: &str, tz_offset: Option<i32>) -> Result<(u64, i32)> {
+    let d = NaiveDate::parse_from_str(&s.replace('_', "-"), "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid date: {}", s))?;
+    at_offset(d.and_hms_opt(0, 0, 0).unwrap(), tz_offset)
+}
+
+/// Removes the characters that can't be part of a stream name.
+fn clean_stream_name(s: &str) -> String {
+    String::from(s.replace(['{', '}'], "").trim())
+}
+
+/// Returns the UTC offset at the timestamp `secs` of the time zone `name` (eg: `Europe/Paris`).
+fn zone_offset(name: &str, secs: u64) -> Option<i32> {
+    let tz = name.parse::<Tz>().ok()?;
+    let t = Utc.timestamp_opt(secs as i64, 0).single()?;
+    Some(
+        tz.offset_from_utc_datetime(&t.naive_utc())
+            .fix()
+            .local_minus_utc(),
+    )
+}
+
+/// Splits a text in a title (its first non-empty line, without heading markers) and a body.
+fn split_title(text: &str) -> (String, String) {
+    let text = text.trim_start();
+    let (first, rest) = match text.find('\n') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, ""),
+    };
+    (
+        String::from(first.trim_start_matches('#').trim()),
+        String::from(rest.trim_start_matches('\n').trim_end()),
+    )
+}
+
+/// Day One escapes Markdown punctuation in its exports (`\.`, `\-`, ...).
+fn unescape_day_one(s: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\\([\\.!()\-#*_\[\]{}+`>])").unwrap();
+    }
+    String::from(RE.replace_all(s, "$1"))
+}
+
+fn string_field(o: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .find_map(|k| o.get(*k).and_then(|v| v.as_str()).map(String::from))
+}
+
+fn tags_field(o: &Value) -> Vec<String> {
+    match o.get("tags").and_then(|v| v.as_array()) {
+        Some(tags) => tags
+            .iter()
+            .filter_map(|t| t.as_str())
+            .map(clean_stream_name)
+            .filter(|t| !t.is_empty())
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Parses a Day One JSON export. The journal name (the file name in Day One exports) is added to
+/// the streams of each entry along with its tags. The UTC offset of entries comes from their
+/// `timeZone`, dates being in UTC.
+pub fn parse_day_one(
+    content: &str,
+    journal: &str,
+    tz_offset: Option<i32>,
+) -> Result<Vec<Result<Imported>>> {
+    let v: Value = serde_json::from_str(content)?;
+    let entries = v
+        .get("entries")
+        .and_then(|e| e.as_array())
+        .ok_or_else(|| anyhow!("Missing `entries` in Day One export"))?;
+
+    Ok(entries
+        .iter()
+        .map(|o| {
+            let (created, offset) = parse_timestamp(
+                o.get("creationDate")
+                    .ok_or_else(|| anyhow!("Missing `creationDate`"))?,
+                tz_offset,
+            )?;
+            let tz_offset = string_field(o, &["timeZone"])
+                .and_then(|z| zone_offset(&z, created))
+                .unwrap_or(offset);
+            let text = unescape_day_one(&string_field(o, &["text"]).unwrap_or_default());
+            let (title, body) = split_title(&text);
+            let mut streams = vec![clean_stream_name(journal)];
+            streams.append(&mut tags_field(o));
+            Ok(Imported {
+                source_id: string_field(o, &["uuid"]).map(|id| format!("dayone:{}", id)),
+                created,
+                tz_offset,
+                streams,
+                title,
+                body,
+            })
+        })
+        .collect())
+}
+
+/// Parses a Journey or Diarium JSON export, which contains either a single entry or an array of
+/// entries.
+pub fn parse_journey(content: &str, tz_offset: Option<i32>) -> Result<Vec<Result<Imported>>> {
+    let v: Value = serde_json::from_str(content)?;
+    let entries = match v {
+        Value::Array(a) => a,
+        o => vec![o],
+    };
+
+    Ok(entries
+        .iter()
+        .map(|o| {
+            let (created, tz_offset) = parse_timestamp(
+                ["date_journal", "date", "created", "creationDate"]
+                    .iter()
+                    .find_map(|k| o.get(*k))
+                    .ok_or_else(|| anyhow!("Missing entry date"))?,
+                tz_offset,
+            )?;
+            let text = string_field(o, &["text", "content", "body"]).unwrap_or_default();
+            let (title, body) = match string_field(o, &["title", "heading"]) {
+                Some(t) if !t.trim().is_empty() => (t, text),
+                _ => split_title(&text),
+            };
+            Ok(Imported {
+                source_id: string_field(o, &["id"]).map(|id| format!("journey:{}", id)),
+                created,
+                tz_offset,
+                streams: tags_field(o),
+                title,
+                body,
+            })
+        })
+        .collect())
+}
+
+/// Parses a daily note. The date comes from the file name (which identifies the note on later
+/// imports) and inline `#tags` are mapped to streams.
+pub fn parse_daily_note(name: &str, content: &str, tz_offset: Option<i32>) -> Result<Imported> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?:^|\s)#([\w/-]+)").unwrap();
+    }
+    let (created, tz_offset) = parse_date(name, tz_offset)?;
+    let mut streams: Vec<String> = vec![];
+    RE.captures_iter(content).for_each(|c| {
+        let t = String::from(&c[1]);
+        if !streams.contains(&t) {
+            streams.push(t);
+        }
+    });
+    Ok(Imported {
+        source_id: Some(format!("daily:{}", name.replace('_', "-"))),
+        created,
+        tz_offset,
+        streams,
+        title: name.replace('_', "-"),
+        body: String::from(content.trim_end()),
+    })
+}
+
+impl DB {
+    /// Imports the export of another journaling app. Imported entries keep their original
+    /// creation time, and the ones already imported (see `Imported.source_id`) are skipped.
+    pub fn import_from(&self, options: &ImporterOptions) -> Result<ImportReport> {
+        let mut sources = self.imported_sources()?;
+        let path = Path::new(&options.path);
+        let ext = match options.format {
+            Format::DailyNotes => "md",
+            _ => "json",
+        };
+        let files = if path.is_dir() {
+            list_files(path, ext)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        let mut report = ImportReport {
+            dry_run: options.dry_run,
+            ..ImportReport::default()
+        };
+
+        for f in files {
+            let name = f
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let parsed = fs::read_to_string(&f)
+                .map_err(|err| anyhow!(err))
+                .and_then(|content| match options.format {
+                    Format::DayOne => parse_day_one(&content, &name, options.tz_offset),
+                    Format::Journey => parse_journey(&content, options.tz_offset),
+                    Format::DailyNotes => {
+                        Ok(vec![parse_daily_note(&name, &content, options.tz_offset)])
+                    }
+                });
+            let imported = match parsed {
+                Ok(i) => i,
+                Err(err) => {
+                    report.errors.push(ImportError {
+                        path: f.display().to_string(),
+                        error: format!("{}", err),
+                    });
+                    continue;
+                }
+            };
+
+            for (i, r) in imported.into_iter().enumerate() {
+                let path = match options.format {
+                    Format::DailyNotes => f.display().to_string(),
+                    _ => format!("{}#{}", f.display(), i),
+                };
+                match r.and_then(|i| {
+                    self.import_entry(i, &options.meta, options.dry_run, &mut sources)
+                }) {
+                    Ok((id, action)) => {
+                        match action {
+                            ImportAction::Skipped => report.skipped += 1,
+                            _ => report.created += 1,
+                        }
+                        report.files.push(ImportFile { path, id, action });
+                    }
+                    Err(err) => report.errors.push(ImportError {
+                        path,
+                        error: format!("{}", err),
+                    }),
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the ids of the entries imported from other apps by source id, see
+    /// `Imported.source_id`.
+    fn imported_sources(&self) -> Result<HashMap<String, String>> {
+        let mut sources = HashMap::new();
+        for id in self.entries_with_field("source")? {
+            if let Some(e) = self.get_entry(&id)? {
+                if let Some(Field::Text(s)) = e.fields.get("source") {
+                    sources.insert(s.clone(), id);
+                }
+            }
+        }
+        Ok(sources)
+    }
+
+    fn import_entry(
+        &self,
+        imported: Imported,
+        meta: &str,
+        dry_run: bool,
+        sources: &mut HashMap<String, String>,
+    ) -> Result<(String, ImportAction)> {
+        if let Some(id) = imported.source_id.as_ref().and_then(|s| sources.get(s)) {
+            return Ok((id.clone(), ImportAction::Skipped));
+        }
+
+        let mut m = vec![];
+        for s in imported.streams.iter() {
+            validate_stream_name(s)?;
+            m.push(format!("{{{}}}", s));
+        }
+        if let Some(s) = &imported.source_id {
+            m.push(format!("source:{}", Field::Text(s.clone()).to_token()));
+        }
+        if !meta.trim().is_empty() {
+            m.push(String::from(meta.trim()));
+        }
+
+        let entry = Entry {
+            id: generate_id(imported.created),
+            created: imported.created,
+            tz_offset: Some(imported.tz_offset),
+            meta: m.join(" "),
+            title: imported.title,
+            body: imported.body,
+            ..Entry::default()
+        };
+        if !dry_run {
+            self.insert_entry(&entry)?;
+        }
+        if let Some(s) = imported.source_id {
+            sources.insert(s, entry.id.clone());
+        }
+        Ok((entry.id, ImportAction::Created))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn test_parse_day_one() {
+        let content = r##"{
+          "metadata": {"version": "1.0"},
+          "entries": [
+            {
+              "uuid": "A1B2",
+              "creationDate": "2021-03-01T10:00:00Z",
+              "timeZone": "Europe/Paris",
+              "text": "# Meeting notes\n\nDiscussed v1\\.2 \\- shipping",
+              "tags": ["work", "{ideas}"]
+            },
+            {"text": "No date"}
+          ]
+        }"##;
+        let entries = parse_day_one(content, "Journal", None).unwrap();
+        assert_eq!(
+            &Imported {
+                source_id: Some(String::from("dayone:A1B2")),
+                created: 1614592800,
+                tz_offset: 3600,
+                streams: vec![
+                    String::from("Journal"),
+                    String::from("work"),
+                    String::from("ideas")
+                ],
+                title: String::from("Meeting notes"),
+                body: String::from("Discussed v1.2 - shipping"),
+            },
+            entries[0].as_ref().unwrap()
+        );
+        assert!(entries[1].is_err());
+    }
+
+    #[test]
+    fn test_parse_journey() {
+        let content = r#"[
+          {"date_journal": 1614592800000, "text": "Hello\nWorld", "tags": ["Travel"]},
+          {"date": "2021-03-01 10:00:00", "heading": "Diarium", "text": "Body"}
+        ]"#;
+        let entries = parse_journey(content, Some(0)).unwrap();
+        let e = entries[0].as_ref().unwrap();
+        assert_eq!(
+            (1614592800, "Hello", "World", vec![String::from("Travel")]),
+            (
+                e.created,
+                e.title.as_str(),
+                e.body.as_str(),
+                e.streams.clone()
+            )
+        );
+        let e = entries[1].as_ref().unwrap();
+        assert_eq!(
+            (1614592800, "Diarium", "Body"),
+            (e.created, e.title.as_str(), e.body.as_str())
+        );
+
+        // Dates without time zone are read at the given UTC offset.
+        let e = parse_journey(content, Some(3600)).unwrap()[1]
+            .as_ref()
+            .unwrap()
+            .clone();
+        assert_eq!((1614589200, 3600), (e.created, e.tz_offset));
+    }
+
+    #[test]
+    fn test_parse_daily_note() {
+        let e = parse_daily_note(
+            "2021_03_01",
+            "- met #alice about #work/acme\n- #work again",
+            Some(0),
+        )
+        .unwrap();
+        assert_eq!((1614556800, 0), (e.created, e.tz_offset));
+        assert_eq!("2021-03-01", e.title);
+        assert_eq!(vec!["alice", "work/acme", "work"], e.streams);
+        assert!(parse_daily_note("Inbox", "", None).is_err());
+    }
+
+    #[test]
+    fn test_import_from() {
+        let dir = std::env::temp_dir().join(format!("dump-test-{}", nanoid::nanoid!()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("export.json"),
+            r#"[
+              {"id": "j-1", "date": "2021-03-01 00:30:00", "text": "Late\nnight"},
+              {"id": "j-2", "date": "2021-03-02 10:00:00", "text": "Morning"}
+            ]"#,
+        )
+        .unwrap();
+
+        let db = test_db();
+        let options = ImporterOptions {
+            format: Format::Journey,
+            path: dir.display().to_string(),
+            meta: String::from("{Journey}"),
+            tz_offset: Some(3600),
+            dry_run: false,
+        };
+        let report = db.import_from(&options).unwrap();
+        assert_eq!((2, 0), (report.created, report.skipped));
+        let e = db.get_entry(&report.files[0].id).unwrap().unwrap();
+        assert_eq!(Some(3600), e.tz_offset);
+        assert_eq!(NaiveDate::from_ymd_opt(2021, 3, 1), Some(e.date()));
+
+        // A second run skips the entries already imported.
+        let report = db.import_from(&options).unwrap();
+        assert_eq!((0, 2), (report.created, report.skipped));
+        assert_eq!(2, db.list_entries("{Journey}", 0, 10).unwrap().0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,6 @@
+// FFI functions receive raw C strings from the app and are responsible for reading them.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use anyhow::Result;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
@@ -6,8 +9,31 @@ use std::ffi::{CStr, CString};
 use std::os::raw;
 use std::path::Path;
 
-mod db;
+pub mod backup;
+pub mod catalog;
+pub mod db;
+pub mod digest;
+pub mod doctor;
+pub mod feed;
+pub mod fields;
+pub mod fuzzy;
+pub mod importers;
+pub mod links;
+pub mod markdown;
+pub mod merge;
+pub mod migrations;
 pub mod models;
+pub mod postprocess;
+pub mod query;
+pub mod related;
+pub mod schedules;
+pub mod server;
+pub mod site;
+pub mod sort;
+pub mod stats;
+pub mod tasks;
+pub mod templates;
+pub mod time;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct EntryList {
@@ -27,6 +53,15 @@ pub struct ListOptions {
     pub query: String,
     pub offset: usize,
     pub limit: usize,
+    /// Whether archived streams are listed by `list_streams`.
+    #[serde(default)]
+    pub archived: bool,
+    /// Order of the entries listed by `list_entries`.
+    #[serde(default)]
+    pub sort: sort::Sort,
+    /// Whether `list_entries` matches the query fuzzily, as with a `~` suffix (see `fuzzy`).
+    #[serde(default)]
+    pub fuzzy: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -45,19 +80,24 @@ pub extern "C" fn response_free_ffi(response: *mut raw::c_char) {
         if response.is_null() {
             return;
         }
-        CString::from_raw(response)
+        drop(CString::from_raw(response))
     };
 }
 
 fn list_entries(options: ListOptions) -> Result<EntryList> {
-    let (total, entries) = DB
-        .list_entries(&options.query, options.offset, options.limit)
-        .unwrap();
+    let query = if options.fuzzy && !options.query.trim_end().ends_with('~') {
+        format!("{}~", options.query)
+    } else {
+        options.query.clone()
+    };
+    let (total, entries) =
+        DB.list_entries_sorted(&query, options.sort, options.offset, options.limit)?;
 
     tracing::debug!(
         query = options.query.as_str(),
         offset = options.offset,
         limit = options.limit,
+        fuzzy = options.fuzzy,
         total,
         "list_entries",
     );
@@ -84,7 +124,13 @@ macro_rules! make_ffi {
                         error: format!("{}", err),
                     })
                     .unwrap(),
-                    Ok(r) => serde_json::to_string(&$function(r).unwrap()).unwrap(),
+                    Ok(r) => match $function(r) {
+                        Err(err) => serde_json::to_string(&ErrorResponse {
+                            error: format!("{}", err),
+                        })
+                        .unwrap(),
+                        Ok(r) => serde_json::to_string(&r).unwrap(),
+                    },
                 }
             }
         };
@@ -119,7 +165,7 @@ pub extern "C" fn create_entry_ffi(request: *const raw::c_char) -> *mut raw::c_c
 
 fn update_entry(update: models::Entry) -> Result<models::Entry> {
     // If the entry does not exist anymore, re-create it as we don't want to loose data. It will
-    // get created with a new ID and creation date.
+    // get created with a new ID.
     let mut entry = match DB.get_entry(&update.id)? {
         Some(e) => e,
         None => {
@@ -127,15 +173,26 @@ fn update_entry(update: models::Entry) -> Result<models::Entry> {
                 meta: update.meta.clone(),
                 title: update.title.clone(),
                 body: update.body.clone(),
+                created: Some(update.created).filter(|c| *c > 0),
+                tz_offset: update.tz_offset,
             };
             DB.create_entry(&create)?
-        },
+        }
     };
     entry.title = update.title;
     entry.body = update.body;
     entry.meta = update.meta;
+    // The creation time can be changed (backdating), the ID stays the same.
+    if update.created > 0 {
+        entry.created = update.created;
+    }
+    if update.tz_offset.is_some() {
+        entry.tz_offset = update.tz_offset;
+    }
 
     DB.insert_entry(&entry)?;
+    // Return the entry as stored, with its fields parsed and normalized.
+    let entry = DB.get_entry(&entry.id)?.unwrap_or(entry);
 
     tracing::debug!(
         id = entry.id.clone().as_str(),
@@ -166,11 +223,248 @@ pub extern "C" fn delete_entry_ffi(request: *const raw::c_char) -> *mut raw::c_c
     make_ffi!(delete_entry, request, models::Entry)
 }
 
-fn list_streams(_options: ListOptions) -> Result<StreamList> {
-    let streams: Vec<models::Stream> = DB.list_streams()?;
+fn get_entry(request: models::EntryRef) -> Result<links::EntryLinks> {
+    let entry = match DB.get_entry_links(&request.id)? {
+        Some(e) => e,
+        None => return Err(anyhow::anyhow!("Unknown entry: {}", request.id)),
+    };
+
+    tracing::debug!(
+        id = request.id.as_str(),
+        outgoing = entry.outgoing.len(),
+        incoming = entry.incoming.len(),
+        "get_entry",
+    );
+
+    Ok(entry)
+}
+
+#[no_mangle]
+pub extern "C" fn get_entry_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(get_entry, request, models::EntryRef)
+}
+
+fn get_backlinks(request: models::EntryRef) -> Result<EntryList> {
+    let entries = DB.get_backlinks(&request.id)?;
+    let total = entries.len();
+
+    tracing::debug!(id = request.id.as_str(), total, "get_backlinks");
+
+    Ok(EntryList {
+        entries,
+        total,
+        offset: 0,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn get_backlinks_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(get_backlinks, request, models::EntryRef)
+}
+
+fn set_template(template: templates::Template) -> Result<templates::Template> {
+    DB.set_template(&template)?;
+
+    tracing::debug!(name = template.name.as_str(), "set_template",);
+
+    Ok(template)
+}
+
+#[no_mangle]
+pub extern "C" fn set_template_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(set_template, request, templates::Template)
+}
+
+fn list_templates(_options: ListOptions) -> Result<templates::TemplateList> {
+    let templates = DB.list_templates()?;
+    let total = templates.len();
+
+    tracing::debug!(total, "list_templates",);
+
+    Ok(templates::TemplateList { total, templates })
+}
+
+#[no_mangle]
+pub extern "C" fn list_templates_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(list_templates, request, ListOptions)
+}
+
+fn delete_template(delete: templates::TemplateRef) -> Result<templates::TemplateRef> {
+    DB.delete_template(&delete.name)?;
+
+    tracing::debug!(name = delete.name.as_str(), "delete_template",);
+
+    Ok(delete)
+}
+
+#[no_mangle]
+pub extern "C" fn delete_template_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(delete_template, request, templates::TemplateRef)
+}
+
+fn get_or_create_daily(request: templates::DailyRequest) -> Result<models::Entry> {
+    let date = templates::parse_date(request.date.as_deref(), time::today())?;
+    let entry = DB.get_or_create_daily(&request.stream, date)?;
+
+    tracing::debug!(
+        stream = request.stream.as_str(),
+        date = %date,
+        id = entry.id.as_str(),
+        "get_or_create_daily",
+    );
+
+    Ok(entry)
+}
+
+#[no_mangle]
+pub extern "C" fn get_or_create_daily_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(get_or_create_daily, request, templates::DailyRequest)
+}
+
+fn set_schedule(schedule: schedules::Schedule) -> Result<schedules::Schedule> {
+    let schedule = DB.set_schedule(&schedule)?;
+
+    tracing::debug!(
+        name = schedule.name.as_str(),
+        cron = schedule.cron.as_str(),
+        "set_schedule",
+    );
+
+    Ok(schedule)
+}
+
+#[no_mangle]
+pub extern "C" fn set_schedule_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(set_schedule, request, schedules::Schedule)
+}
+
+fn list_schedules(_options: ListOptions) -> Result<schedules::ScheduleList> {
+    let schedules = DB.list_schedules()?;
+    let total = schedules.len();
+
+    tracing::debug!(total, "list_schedules",);
+
+    Ok(schedules::ScheduleList { total, schedules })
+}
+
+#[no_mangle]
+pub extern "C" fn list_schedules_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(list_schedules, request, ListOptions)
+}
+
+fn delete_schedule(delete: schedules::ScheduleRef) -> Result<schedules::ScheduleRef> {
+    DB.delete_schedule(&delete.name)?;
+
+    tracing::debug!(name = delete.name.as_str(), "delete_schedule",);
+
+    Ok(delete)
+}
+
+#[no_mangle]
+pub extern "C" fn delete_schedule_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(delete_schedule, request, schedules::ScheduleRef)
+}
+
+fn stats(request: stats::StatsRequest) -> Result<stats::Stats> {
+    let stats = DB.stats(&request.query)?;
+
+    tracing::debug!(
+        query = request.query.as_str(),
+        entries = stats.entries,
+        words = stats.words,
+        "stats",
+    );
+
+    Ok(stats)
+}
+
+#[no_mangle]
+pub extern "C" fn stats_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(stats, request, stats::StatsRequest)
+}
+
+fn heatmap(request: stats::HeatmapRequest) -> Result<stats::Heatmap> {
+    let today = time::today();
+    let to = templates::parse_date(request.to.as_deref(), today)?;
+    let from = match request.from.as_deref() {
+        Some(from) => templates::parse_date(Some(from), today)?,
+        None => to - chrono::Duration::days(stats::HEATMAP_DAYS - 1),
+    };
+    let heatmap = DB.heatmap(&request.query, from, to)?;
+
+    tracing::debug!(
+        query = request.query.as_str(),
+        from = heatmap.from.as_str(),
+        to = heatmap.to.as_str(),
+        "heatmap",
+    );
+
+    Ok(heatmap)
+}
+
+#[no_mangle]
+pub extern "C" fn heatmap_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(heatmap, request, stats::HeatmapRequest)
+}
+
+fn digest(request: digest::DigestRequest) -> Result<digest::Digest> {
+    let digest = DB.digest(&request.query, &request.period, request.save.as_deref())?;
+
+    tracing::debug!(
+        query = request.query.as_str(),
+        period = digest.period.as_str(),
+        entries = digest.entries,
+        "digest",
+    );
+
+    Ok(digest)
+}
+
+#[no_mangle]
+pub extern "C" fn digest_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(digest, request, digest::DigestRequest)
+}
+
+fn related_entries(request: related::RelatedRequest) -> Result<related::RelatedList> {
+    let entries = DB.related_entries(&request.id, request.k)?;
+
+    tracing::debug!(
+        id = request.id.as_str(),
+        k = request.k,
+        count = entries.len(),
+        "related_entries",
+    );
+
+    Ok(related::RelatedList { entries })
+}
+
+#[no_mangle]
+pub extern "C" fn related_entries_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(related_entries, request, related::RelatedRequest)
+}
+
+fn toggle_task(toggle: tasks::TaskToggle) -> Result<models::Entry> {
+    let entry = DB.toggle_task(&toggle.id, toggle.index)?;
+
+    tracing::debug!(id = entry.id.as_str(), index = toggle.index, "toggle_task",);
+
+    Ok(entry)
+}
+
+#[no_mangle]
+pub extern "C" fn toggle_task_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(toggle_task, request, tasks::TaskToggle)
+}
+
+fn list_streams(options: ListOptions) -> Result<StreamList> {
+    let streams: Vec<models::Stream> = DB
+        .list_streams()?
+        .into_iter()
+        .filter(|s| options.archived || !s.attributes().archived)
+        .collect();
     let total = streams.len();
 
-    tracing::debug!(total, "list_streams",);
+    tracing::debug!(archived = options.archived, total, "list_streams",);
 
     Ok(StreamList { streams, total })
 }
@@ -180,6 +474,29 @@ pub extern "C" fn list_streams_ffi(request: *const raw::c_char) -> *mut raw::c_c
     make_ffi!(list_streams, request, ListOptions)
 }
 
+fn search_streams(search: fuzzy::StreamSearch) -> Result<StreamList> {
+    let streams: Vec<models::Stream> = DB
+        .search_streams(&search.name, search.fuzzy)?
+        .into_iter()
+        .filter(|s| search.archived || !s.attributes().archived)
+        .collect();
+    let total = streams.len();
+
+    tracing::debug!(
+        name = search.name.as_str(),
+        fuzzy = search.fuzzy,
+        total,
+        "search_streams",
+    );
+
+    Ok(StreamList { streams, total })
+}
+
+#[no_mangle]
+pub extern "C" fn search_streams_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(search_streams, request, fuzzy::StreamSearch)
+}
+
 fn delete_stream(delete: models::Stream) -> Result<models::Stream> {
     DB.delete_stream(&delete.id)?;
 
@@ -194,22 +511,32 @@ pub extern "C" fn delete_stream_ffi(request: *const raw::c_char) -> *mut raw::c_
 }
 
 fn update_stream(update: models::Stream) -> Result<models::Stream> {
-    let stream = DB.get_stream(&update.id)?;
-    if stream.is_none() {
-        Ok(update)
-    } else {
-        let mut stream = stream.unwrap();
-        stream.name = update.name;
+    match DB.get_stream(&update.id)? {
+        None => Ok(update),
+        Some(stream) => {
+            // Attributes are validated and stored with their current version. An empty `meta`
+            // (eg: from apps unaware of attributes) keeps the stored ones.
+            let meta = match update.meta.trim().is_empty() {
+                true => None,
+                false => Some(
+                    models::StreamAttributes::parse(&update.meta)
+                        .map_err(|err| anyhow::anyhow!("Invalid stream attributes: {}", err))?
+                        .to_meta(),
+                ),
+            };
 
-        DB.insert_stream(&stream).unwrap();
+            // Renames go through `update_stream` so that children follow their parent, the name
+            // and attributes being written together.
+            let (stream, _) = DB.update_stream(&stream.id, &update.name, meta.as_deref())?;
 
-        tracing::debug!(
-            id = stream.id.clone().as_str(),
-            name = stream.name.clone().as_str(),
-            "update_stream",
-        );
+            tracing::debug!(
+                id = stream.id.clone().as_str(),
+                name = stream.name.clone().as_str(),
+                "update_stream",
+            );
 
-        Ok(stream)
+            Ok(stream)
+        }
     }
 }
 
@@ -217,3 +544,202 @@ fn update_stream(update: models::Stream) -> Result<models::Stream> {
 pub extern "C" fn update_stream_ffi(request: *const raw::c_char) -> *mut raw::c_char {
     make_ffi!(update_stream, request, models::Stream)
 }
+
+fn rename_stream(rename: models::StreamRename) -> Result<StreamList> {
+    // With `merge`, renaming to the name of an existing stream merges into it instead.
+    if rename.merge {
+        if let Some(target) = DB.stream_by_name(&rename.name, false)? {
+            let report = DB.merge_streams(&rename.id, &target.id)?;
+            let total = report.moved.len();
+
+            tracing::debug!(
+                id = rename.id.as_str(),
+                name = rename.name.as_str(),
+                total,
+                "rename_stream",
+            );
+
+            return Ok(StreamList {
+                streams: report.moved,
+                total,
+            });
+        }
+    }
+
+    let streams = DB.rename_stream(&rename.id, &rename.name)?;
+    let total = streams.len();
+
+    tracing::debug!(
+        id = rename.id.as_str(),
+        name = rename.name.as_str(),
+        total,
+        "rename_stream",
+    );
+
+    Ok(StreamList { streams, total })
+}
+
+#[no_mangle]
+pub extern "C" fn rename_stream_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(rename_stream, request, models::StreamRename)
+}
+
+fn merge_streams(request: merge::StreamMerge) -> Result<merge::MergeReport> {
+    let report = DB.merge_streams(&request.source, &request.target)?;
+
+    tracing::debug!(
+        source = request.source.as_str(),
+        target = request.target.as_str(),
+        merged = report.merged.len(),
+        entries = report.entries,
+        "merge_streams",
+    );
+
+    Ok(report)
+}
+
+#[no_mangle]
+pub extern "C" fn merge_streams_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(merge_streams, request, merge::StreamMerge)
+}
+
+fn export_markdown(options: markdown::ExportOptions) -> Result<markdown::ExportReport> {
+    let path = String::from(shellexpand::tilde(&options.path));
+    let total = DB.export_markdown(&path, &options.query)?;
+
+    tracing::debug!(
+        path = path.as_str(),
+        query = options.query.as_str(),
+        total,
+        "export_markdown",
+    );
+
+    Ok(markdown::ExportReport { path, total })
+}
+
+#[no_mangle]
+pub extern "C" fn export_markdown_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(export_markdown, request, markdown::ExportOptions)
+}
+
+fn import_markdown(options: markdown::ImportOptions) -> Result<markdown::ImportReport> {
+    let path = String::from(shellexpand::tilde(&options.path));
+    let report = DB.import_markdown(&path, options.dry_run)?;
+
+    tracing::debug!(
+        path = path.as_str(),
+        dry_run = report.dry_run,
+        created = report.created,
+        updated = report.updated,
+        skipped = report.skipped,
+        errors = report.errors.len(),
+        "import_markdown",
+    );
+
+    Ok(report)
+}
+
+#[no_mangle]
+pub extern "C" fn import_markdown_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(import_markdown, request, markdown::ImportOptions)
+}
+
+fn backup(options: backup::BackupOptions) -> Result<backup::BackupReport> {
+    let path = String::from(shellexpand::tilde(&options.path));
+    let file = std::fs::File::create(&path)?;
+    let mut report = DB.export_jsonl(std::io::BufWriter::new(file))?;
+    report.path = path;
+
+    tracing::debug!(
+        path = report.path.as_str(),
+        streams = report.streams,
+        entries = report.entries,
+        "backup",
+    );
+
+    Ok(report)
+}
+
+#[no_mangle]
+pub extern "C" fn backup_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(backup, request, backup::BackupOptions)
+}
+
+fn import_from(options: importers::ImporterOptions) -> Result<markdown::ImportReport> {
+    let path = String::from(shellexpand::tilde(&options.path));
+    let report = DB.import_from(&importers::ImporterOptions { path, ..options })?;
+
+    tracing::debug!(
+        dry_run = report.dry_run,
+        created = report.created,
+        errors = report.errors.len(),
+        "import_from",
+    );
+
+    Ok(report)
+}
+
+#[no_mangle]
+pub extern "C" fn import_from_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(import_from, request, importers::ImporterOptions)
+}
+
+fn generate_site(options: site::SiteOptions) -> Result<site::SiteReport> {
+    let path = String::from(shellexpand::tilde(&options.path));
+    let report = DB.generate_site(&site::SiteOptions { path, ..options })?;
+
+    tracing::debug!(
+        path = report.path.as_str(),
+        entries = report.entries,
+        streams = report.streams,
+        "generate_site",
+    );
+
+    Ok(report)
+}
+
+#[no_mangle]
+pub extern "C" fn generate_site_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(generate_site, request, site::SiteOptions)
+}
+
+fn doctor(request: doctor::DoctorRequest) -> Result<doctor::DoctorResponse> {
+    let response = DB.doctor(&request)?;
+
+    tracing::debug!(
+        action = format!("{:?}", request.action).as_str(),
+        key = request.key.as_str(),
+        quarantined = response.quarantined.len(),
+        "doctor",
+    );
+
+    Ok(response)
+}
+
+#[no_mangle]
+pub extern "C" fn doctor_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(doctor, request, doctor::DoctorRequest)
+}
+
+fn postprocess(
+    options: postprocess::PostprocessOptions,
+) -> Result<postprocess::PostprocessProgress> {
+    let progress = DB.postprocess(options.batch, &mut |_| {})?;
+
+    tracing::debug!(
+        version = progress.version,
+        processed = progress.processed,
+        total = progress.total,
+        done = progress.done,
+        "postprocess",
+    );
+
+    Ok(progress)
+}
+
+/// Runs a batch of the postprocessing pass (see `DB::postprocess`). The pass already runs in the
+/// background once the database is opened: the app may call it to report progress.
+#[no_mangle]
+pub extern "C" fn postprocess_ffi(request: *const raw::c_char) -> *mut raw::c_char {
+    make_ffi!(postprocess, request, postprocess::PostprocessOptions)
+}
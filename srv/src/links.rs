@@ -0,0 +1,341 @@
+use crate::db::{key, DB};
+use crate::models::Entry;
+use crate::sort;
+use anyhow::Result;
+use bincode::deserialize;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+// The `links` tree holds, with `\0` separated keys:
+// - `out\0<source>\0<target>` and `in\0<target>\0<source>` for each resolved link, with the text
+//   of the link as value.
+// - `pending\0<text>\0<source>` for links that don't resolve (yet) to an entry, `text` being
+//   lowercased, and `pending_src\0<source>\0<text>` to find them by source.
+// The `titles` tree indexes entries by title, see `sort::title_key`.
+
+/// An entry linked to or from another entry.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Link {
+    pub id: String,
+    pub title: String,
+}
+
+/// An entry along with its outgoing links, incoming links (backlinks) and the links of its body
+/// that don't resolve to any entry.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EntryLinks {
+    #[serde(flatten)]
+    pub entry: Entry,
+    pub outgoing: Vec<Link>,
+    pub incoming: Vec<Link>,
+    pub unresolved: Vec<String>,
+}
+
+lazy_static! {
+    static ref LINK_RE: Regex = Regex::new(r"\[\[([^\[\]\|]+)(\|[^\[\]]*)?\]\]").unwrap();
+}
+
+/// Extracts the targets of the `[[Entry title]]` or `[[id]]` links of `body` (the optional
+/// `|label` of a link is ignored).
+pub fn extract(body: &str) -> Vec<String> {
+    let mut targets: Vec<String> = vec![];
+    for c in LINK_RE.captures_iter(body) {
+        let t = c[1].trim().to_string();
+        if !t.is_empty() && !targets.contains(&t) {
+            targets.push(t);
+        }
+    }
+    targets
+}
+
+/// Rewrites the links to `from` in `body` so that they point to `to`, keeping their label.
+pub fn rename(body: &str, from: &str, to: &str) -> String {
+    LINK_RE
+        .replace_all(body, |c: &Captures| {
+            if c[1].trim().to_lowercase() == from.to_lowercase() {
+                format!("[[{}{}]]", to, c.get(2).map(|m| m.as_str()).unwrap_or(""))
+            } else {
+                c[0].to_string()
+            }
+        })
+        .to_string()
+}
+
+fn prefix(parts: &[&str]) -> Vec<u8> {
+    let mut k = key(parts);
+    k.push(0);
+    k
+}
+
+/// Returns the last component of a `\0` separated key.
+fn last(k: &[u8]) -> String {
+    let s = String::from_utf8_lossy(k);
+    s.rsplit('\0').next().unwrap_or("").to_string()
+}
+
+impl DB {
+    fn resolve_link(&self, text: &str) -> Result<Option<String>> {
+        if self.entries.contains_key(text)? {
+            return Ok(Some(text.to_string()));
+        }
+        match self
+            .titles
+            .scan_prefix(prefix(&[&text.to_lowercase()]))
+            .next()
+        {
+            Some(x) => Ok(Some(last(&x?.0))),
+            None => Ok(None),
+        }
+    }
+
+    fn unlink_source(&self, id: &str) -> Result<()> {
+        for x in self.links.scan_prefix(prefix(&["out", id])) {
+            let (k, _) = x?;
+            self.links.remove(&k)?;
+            self.links.remove(key(&["in", &last(&k), id]))?;
+        }
+        Ok(())
+    }
+
+    fn add_pending(&self, source: &str, text: &str) -> Result<()> {
+        let text = text.to_lowercase();
+        self.links.insert(key(&["pending", &text, source]), &[])?;
+        self.links
+            .insert(key(&["pending_src", source, &text]), &[])?;
+        Ok(())
+    }
+
+    fn remove_pending(&self, source: &str, text: &str) -> Result<()> {
+        self.links.remove(key(&["pending", text, source]))?;
+        self.links.remove(key(&["pending_src", source, text]))?;
+        Ok(())
+    }
+
+    /// Resolves the pending links to `text` now that it is the title or id of an entry.
+    fn resolve_pending(&self, text: &str) -> Result<()> {
+        let lower = text.to_lowercase();
+        for x in self.links.scan_prefix(prefix(&["pending", &lower])) {
+            let source = last(&x?.0);
+            self.remove_pending(&source, &lower)?;
+            self.link(&source, text)?;
+        }
+        Ok(())
+    }
+
+    fn link(&self, source: &str, text: &str) -> Result<()> {
+        match self.resolve_link(text)? {
+            Some(target) => {
+                self.links
+                    .insert(key(&["out", source, &target]), text.as_bytes())?;
+                self.links
+                    .insert(key(&["in", &target, source]), text.as_bytes())?;
+            }
+            None => self.add_pending(source, text)?,
+        }
+        Ok(())
+    }
+
+    /// Updates the `titles` and `links` trees for `entry`, `previous` being the entry as stored
+    /// before the update. When the title of an entry changes, the links to its previous title
+    /// are rewritten in the bodies of the entries that link to it (keeping their `updated` time).
+    /// Pending links to the new title are resolved.
+    pub(crate) fn index_links(&self, entry: &Entry, previous: Option<&Entry>) -> Result<()> {
+        let renamed = match previous {
+            Some(p) if p.title != entry.title => {
+                self.titles.remove(sort::title_key(p))?;
+                Some(p.title.clone())
+            }
+            _ => None,
+        };
+        self.titles.insert(sort::title_key(entry), &[])?;
+
+        // Outgoing links are recomputed from the body.
+        self.unlink_source(&entry.id)?;
+        for x in self.links.scan_prefix(prefix(&["pending_src", &entry.id])) {
+            self.remove_pending(&entry.id, &last(&x?.0))?;
+        }
+        for text in extract(&entry.body) {
+            self.link(&entry.id, &text)?;
+        }
+
+        if let Some(old) = renamed {
+            if !old.trim().is_empty() {
+                let sources = self
+                    .links
+                    .scan_prefix(prefix(&["in", &entry.id]))
+                    .map(|x| Ok(last(&x?.0)))
+                    .collect::<Result<Vec<_>>>()?;
+                for s in sources {
+                    if s == entry.id {
+                        continue;
+                    }
+                    if let Some(mut e) = self.get_entry(&s)? {
+                        let body = rename(&e.body, &old, &entry.title);
+                        if body != e.body {
+                            e.body = body;
+                            self.restore_entry(&e)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if !entry.title.trim().is_empty() {
+            self.resolve_pending(&entry.title)?;
+        }
+        self.resolve_pending(&entry.id)?;
+
+        Ok(())
+    }
+
+    /// Removes `entry` from the `titles` and `links` trees. Links to it become pending so that
+    /// they resolve again if an entry with the same title is created.
+    pub(crate) fn unindex_links(&self, entry: &Entry) -> Result<()> {
+        self.titles.remove(sort::title_key(entry))?;
+        self.unlink_entry(&entry.id)
+    }
+
+    /// Removes the links from and to the entry `id` from the `links` tree, see `unindex_links`.
+    pub(crate) fn unlink_entry(&self, id: &str) -> Result<()> {
+        self.unlink_source(id)?;
+        for x in self.links.scan_prefix(prefix(&["pending_src", id])) {
+            self.remove_pending(id, &last(&x?.0))?;
+        }
+        for x in self.links.scan_prefix(prefix(&["in", id])) {
+            let (k, v) = x?;
+            let source = last(&k);
+            self.links.remove(&k)?;
+            self.links.remove(key(&["out", &source, id]))?;
+            self.add_pending(&source, &String::from_utf8_lossy(&v))?;
+        }
+        Ok(())
+    }
+
+    fn linked(&self, direction: &str, id: &str) -> Result<Vec<Link>> {
+        self.links
+            .scan_prefix(prefix(&[direction, id]))
+            .map(|x| {
+                let other = last(&x?.0);
+                let title = match self.entries.get(&other)? {
+                    Some(v) => deserialize::<Entry>(&v)?.title,
+                    None => String::from(""),
+                };
+                Ok(Link { id: other, title })
+            })
+            .collect()
+    }
+
+    /// Returns the entries linking to the entry `id`, most recent first.
+    pub fn get_backlinks(&self, id: &str) -> Result<Vec<Entry>> {
+        let mut entries = vec![];
+        for l in self.linked("in", id)?.into_iter().rev() {
+            if let Some(e) = self.get_entry(&l.id)? {
+                entries.push(e);
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn get_entry_links(&self, id: &str) -> Result<Option<EntryLinks>> {
+        let entry = match self.get_entry(&id.to_string())? {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let outgoing = self.linked("out", id)?;
+        let unresolved = extract(&entry.body)
+            .into_iter()
+            .filter(|t| {
+                !outgoing
+                    .iter()
+                    .any(|l| l.id == *t || l.title.to_lowercase() == t.to_lowercase())
+            })
+            .collect();
+        Ok(Some(EntryLinks {
+            incoming: self.linked("in", id)?,
+            outgoing,
+            unresolved,
+            entry,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_links() {
+        assert_eq!(
+            vec!["Foo", "123-abc"],
+            extract("see [[Foo]], [[ 123-abc |there]] and [[]]")
+        );
+        assert_eq!(
+            "[[Bar|label]] [[Baz]]",
+            rename("[[foo|label]] [[Baz]]", "Foo", "Bar")
+        );
+
+        let db = test_db();
+        let entry = |title: &str, body: &str| test_entry(&db, "", title, body);
+        let a = entry("Reading list", "");
+        let b = entry("Notes", "From [[reading list]], see [[Ideas]]");
+        let c = entry("Other", &format!("Also [[{}]]", a.id));
+
+        let l = db.get_entry_links(&b.id).unwrap().unwrap();
+        assert_eq!(
+            vec![a.id.clone()],
+            l.outgoing.iter().map(|l| l.id.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["Ideas"], l.unresolved);
+        let mut backlinks = db
+            .get_backlinks(&a.id)
+            .unwrap()
+            .iter()
+            .map(|e| e.id.clone())
+            .collect::<Vec<_>>();
+        backlinks.sort();
+        let mut expected = vec![b.id.clone(), c.id.clone()];
+        expected.sort();
+        assert_eq!(expected, backlinks);
+
+        // Pending links resolve when an entry with that title is created.
+        let d = entry("Ideas", "");
+        assert_eq!(1, db.get_backlinks(&d.id).unwrap().len());
+
+        // Renaming an entry rewrites the links to its title, without touching their update time.
+        let updated = db.get_entry(&b.id).unwrap().unwrap().updated - 100;
+        let mut b2 = db.get_entry(&b.id).unwrap().unwrap();
+        b2.updated = updated;
+        db.restore_entry(&b2).unwrap();
+        let mut a2 = db.get_entry(&a.id).unwrap().unwrap();
+        a2.title = String::from("Books");
+        db.insert_entry(&a2).unwrap();
+        assert_eq!(
+            "From [[Books]], see [[Ideas]]",
+            db.get_entry(&b.id).unwrap().unwrap().body
+        );
+        assert_eq!(updated, db.get_entry(&b.id).unwrap().unwrap().updated);
+        assert_eq!(2, db.get_backlinks(&a.id).unwrap().len());
+
+        db.delete_entry(&d.id).unwrap();
+        let l = db.get_entry_links(&b.id).unwrap().unwrap();
+        assert_eq!(1, l.outgoing.len());
+        assert_eq!(vec!["Ideas"], l.unresolved);
+        let d = entry("Ideas", "");
+        assert_eq!(1, db.get_backlinks(&d.id).unwrap().len());
+
+        // Pending links go away with the links of their source.
+        let mut e = entry("Todo", "[[Someday]]");
+        e.body = String::from("");
+        db.insert_entry(&e).unwrap();
+        assert_eq!(
+            0,
+            db.links
+                .scan_prefix(prefix(&["pending_src", &e.id]))
+                .count()
+        );
+        let f = entry("Someday", "");
+        assert!(db.get_backlinks(&f.id).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+use crate::db::{generate_id, validate_entry_id, DB};
+use crate::models::Entry;
+use crate::time;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A Markdown file with front matter as written by `DB::export_markdown`. `id`, `created`,
+/// `updated` and `tz_offset` are optional so that hand-written files can be imported as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub id: Option<String>,
+    pub created: Option<u64>,
+    pub updated: Option<u64>,
+    pub tz_offset: Option<i32>,
+    pub meta: String,
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportOptions {
+    pub path: String,
+    #[serde(default)]
+    pub query: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExportReport {
+    pub path: String,
+    pub total: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportOptions {
+    pub path: String,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportAction {
+    Created,
+    Updated,
+    Skipped,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ImportFile {
+    pub path: String,
+    pub id: String,
+    pub action: ImportAction,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ImportError {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub files: Vec<ImportFile>,
+    pub errors: Vec<ImportError>,
+}
+
+impl Document {
+    pub fn from_entry(entry: &Entry) -> Self {
+        Document {
+            id: Some(entry.id.clone()),
+            created: Some(entry.created),
+            updated: Some(entry.updated).filter(|u| *u > 0),
+            tz_offset: entry.tz_offset,
+            meta: entry.meta.clone(),
+            title: entry.title.clone(),
+            body: entry.body.clone(),
+        }
+    }
+
+    /// Renders the document as Markdown with a front matter block. `title` and `meta` are
+    /// written as JSON strings so that any character survives the round-trip.
+    pub fn render(&self) -> String {
+        let mut out = String::from("---\n");
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(created) = self.created {
+            out.push_str(&format!("created: {}\n", created));
+        }
+        if let Some(updated) = self.updated {
+            out.push_str(&format!("updated: {}\n", updated));
+        }
+        if let Some(tz_offset) = self.tz_offset {
+            out.push_str(&format!("tz_offset: {}\n", tz_offset));
+        }
+        out.push_str(&format!(
+            "title: {}\n",
+            serde_json::to_string(&self.title).unwrap()
+        ));
+        out.push_str(&format!(
+            "meta: {}\n",
+            serde_json::to_string(&self.meta).unwrap()
+        ));
+        out.push_str("---\n\n");
+        out.push_str(&self.body);
+        out
+    }
+
+    /// Parses a Markdown file. Files without front matter (or whose leading `---` is never
+    /// closed, eg: a thematic break) are accepted, their content becoming the body of the
+    /// document and `default_title` its title. Line endings are normalized to `\n`.
+    pub fn parse(content: &str, default_title: &str) -> Result<Self> {
+        let content = content.replace("\r\n", "\n");
+        let content = content.as_str();
+        let mut doc = Document {
+            id: None,
+            created: None,
+            updated: None,
+            tz_offset: None,
+            meta: String::from(""),
+            title: String::from(default_title),
+            body: String::from(content),
+        };
+
+        let rest = match content.strip_prefix("---\n") {
+            Some(rest) => rest,
+            None => return Ok(doc),
+        };
+        let (front, body) = match rest.find("\n---\n") {
+            Some(i) => (&rest[..i], &rest[i + 5..]),
+            None => match rest.strip_suffix("\n---") {
+                Some(front) => (front, ""),
+                None => return Ok(doc),
+            },
+        };
+
+        for line in front.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = match line.find(':') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(anyhow!("Invalid front matter line: {}", line)),
+            };
+            let value = if value.starts_with('"') {
+                serde_json::from_str::<String>(value)?
+            } else {
+                String::from(value)
+            };
+            match key {
+                "id" => doc.id = Some(value),
+                "created" => doc.created = Some(value.parse::<u64>()?),
+                "updated" => doc.updated = Some(value.parse::<u64>()?),
+                "tz_offset" => doc.tz_offset = Some(value.parse::<i32>()?),
+                "title" => doc.title = value,
+                "meta" => doc.meta = value,
+                _ => {}
+            }
+        }
+
+        doc.body = String::from(body.strip_prefix('\n').unwrap_or(body));
+        Ok(doc)
+    }
+}
+
+/// Returns all the files with extension `ext` under `dir`, recursively and sorted by path.
+pub(crate) fn list_files(dir: &Path, ext: &str) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = vec![];
+    for e in fs::read_dir(dir)? {
+        let path = e?.path();
+        if path.is_dir() {
+            files.append(&mut list_files(&path, ext)?);
+        } else if path.extension().is_some_and(|x| x == ext) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+impl DB {
+    /// Exports the entries matching `query` to `dir`, one Markdown file per entry named after its
+    /// id.
+    pub fn export_markdown<P: AsRef<Path>>(&self, dir: P, query: &str) -> Result<usize> {
+        fs::create_dir_all(dir.as_ref())?;
+        let (total, entries) = self.list_entries(query, 0, usize::MAX)?;
+        for e in entries.iter() {
+            let path = dir.as_ref().join(format!("{}.md", e.id));
+            fs::write(path, Document::from_entry(e).render())?;
+        }
+        Ok(total)
+    }
+
+    /// Imports a directory of Markdown files. Entries whose `id` already exists are updated (or
+    /// skipped if unchanged), others are created, preserving `id`, `created`, `updated` and
+    /// `tz_offset` when present.
+    /// Errors are reported per file and don't interrupt the import. With `dry_run` nothing is
+    /// written.
+    pub fn import_markdown<P: AsRef<Path>>(&self, dir: P, dry_run: bool) -> Result<ImportReport> {
+        let mut report = ImportReport {
+            dry_run,
+            ..ImportReport::default()
+        };
+
+        for path in list_files(dir.as_ref(), "md")? {
+            match self.import_markdown_file(&path, dry_run) {
+                Ok((id, action)) => {
+                    match action {
+                        ImportAction::Created => report.created += 1,
+                        ImportAction::Updated => report.updated += 1,
+                        ImportAction::Skipped => report.skipped += 1,
+                    }
+                    report.files.push(ImportFile {
+                        path: path.display().to_string(),
+                        id,
+                        action,
+                    });
+                }
+                Err(err) => report.errors.push(ImportError {
+                    path: path.display().to_string(),
+                    error: format!("{}", err),
+                }),
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn import_markdown_file(&self, path: &Path, dry_run: bool) -> Result<(String, ImportAction)> {
+        let content = fs::read_to_string(path)?;
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let doc = Document::parse(&content, &stem)?;
+        if let Some(id) = &doc.id {
+            validate_entry_id(id)?;
+        }
+
+        let existing = match &doc.id {
+            Some(id) => self.get_entry(id)?,
+            None => None,
+        };
+
+        let (entry, action) = match existing {
+            Some(e) => {
+                let created = doc.created.unwrap_or(e.created);
+                let tz_offset = doc.tz_offset.or(e.tz_offset);
+                // An unchanged `updated` lets `insert_entry` move it if the content changed.
+                let updated = doc.updated.filter(|u| *u != e.updated).unwrap_or(0);
+                if e.title == doc.title
+                    && e.meta == doc.meta
+                    && e.body == doc.body
+                    && e.created == created
+                    && e.tz_offset == tz_offset
+                    && updated == 0
+                {
+                    return Ok((e.id, ImportAction::Skipped));
+                }
+                (
+                    Entry {
+                        created,
+                        tz_offset,
+                        updated,
+                        meta: doc.meta,
+                        title: doc.title,
+                        body: doc.body,
+                        ..e
+                    },
+                    ImportAction::Updated,
+                )
+            }
+            None => {
+                // Ids are opaque (see `generate_id`): entries without `created` are created now.
+                let created = doc.created.unwrap_or_else(time::now);
+                (
+                    Entry {
+                        id: doc.id.unwrap_or_else(|| generate_id(created)),
+                        created,
+                        updated: doc.updated.unwrap_or(0),
+                        tz_offset: doc.tz_offset,
+                        meta: doc.meta,
+                        title: doc.title,
+                        body: doc.body,
+                        ..Entry::default()
+                    },
+                    ImportAction::Created,
+                )
+            }
+        };
+
+        if !dry_run {
+            self.restore_entry(&entry)?;
+        }
+
+        Ok((entry.id, action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_document_round_trip() {
+        let doc = Document {
+            id: Some(String::from("1626000000-foo")),
+            created: Some(1626000000),
+            updated: Some(1626000100),
+            tz_offset: Some(-5 * 3600),
+            meta: String::from("{Work} {Work/ProjectX}"),
+            title: String::from("Title: \"quoted\""),
+            body: String::from("---\nfoo\n\n---\n"),
+        };
+        assert_eq!(doc, Document::parse(&doc.render(), "").unwrap());
+
+        let doc = Document::parse("# Hello\n", "hello").unwrap();
+        assert_eq!(None, doc.id);
+        assert_eq!("hello", doc.title);
+        assert_eq!("# Hello\n", doc.body);
+
+        // Unterminated front matter is part of the body.
+        let doc = Document::parse("---\nid: foo\n", "").unwrap();
+        assert_eq!((None, "---\nid: foo\n"), (doc.id, doc.body.as_str()));
+
+        let doc = Document::parse("---\r\nid: foo\r\n---\r\n\r\nBar\r\n", "").unwrap();
+        assert_eq!(
+            (Some(String::from("foo")), "Bar\n"),
+            (doc.id, doc.body.as_str())
+        );
+    }
+
+    #[test]
+    fn test_import_markdown() {
+        let db = test_db();
+        let dir = std::env::temp_dir().join(format!("dump-test-{}", nanoid::nanoid!()));
+
+        let entry = test_entry(&db, "{Work}", "Foo", "Bar");
+        assert_eq!(1, db.export_markdown(&dir, "").unwrap());
+        fs::write(dir.join("broken.md"), "---\ncreated: abc\n---\n").unwrap();
+        fs::write(
+            dir.join("new.md"),
+            "---\nid: 1626000000-new\ncreated: 1626000100\nmeta: {Blog}\n---\n\nHello",
+        )
+        .unwrap();
+
+        let report = db.import_markdown(&dir, true).unwrap();
+        assert_eq!(
+            (1, 0, 1, 1),
+            (
+                report.created,
+                report.updated,
+                report.skipped,
+                report.errors.len()
+            )
+        );
+        assert_eq!(1, db.list_entries("", 0, 10).unwrap().0);
+
+        let report = db.import_markdown(&dir, false).unwrap();
+        assert_eq!((1, 0, 1), (report.created, report.updated, report.skipped));
+        let (total, entries) = db.list_entries("{Blog}", 0, 10).unwrap();
+        assert_eq!(1, total);
+        assert_eq!(1626000100, entries[0].created);
+        assert_eq!("new", entries[0].title);
+
+        let mut doc = Document::from_entry(&entry);
+        doc.body = String::from("Baz");
+        fs::write(dir.join(format!("{}.md", entry.id)), doc.render()).unwrap();
+        let report = db.import_markdown(&dir, false).unwrap();
+        assert_eq!((0, 1, 1), (report.created, report.updated, report.skipped));
+        assert_eq!("Baz", db.get_entry(&entry.id).unwrap().unwrap().body);
+
+        // Creation and update times and UTC offsets are restored, even when only they changed.
+        let e = db.get_entry(&entry.id).unwrap().unwrap();
+        let mut doc = Document::from_entry(&e);
+        doc.created = Some(1626000000);
+        doc.updated = Some(1626000100);
+        doc.tz_offset = Some(3600);
+        fs::write(dir.join(format!("{}.md", entry.id)), doc.render()).unwrap();
+        let report = db.import_markdown(&dir, false).unwrap();
+        assert_eq!((0, 1, 1), (report.created, report.updated, report.skipped));
+        let e = db.get_entry(&entry.id).unwrap().unwrap();
+        assert_eq!(
+            (1626000000, 1626000100, Some(3600)),
+            (e.created, e.updated, e.tz_offset)
+        );
+        let report = db.import_markdown(&dir, false).unwrap();
+        assert_eq!((0, 0, 2), (report.created, report.updated, report.skipped));
+
+        // Ids that aren't safe in paths and index keys are rejected.
+        for (f, id) in [
+            ("up.md", "../../x"),
+            ("sub.md", "a/b"),
+            ("nul.md", "a\\u0000b"),
+        ] {
+            fs::write(dir.join(f), format!("---\nid: \"{}\"\n---\n", id)).unwrap();
+        }
+        let report = db.import_markdown(&dir, false).unwrap();
+        assert_eq!((0, 4), (report.created, report.errors.len()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+use crate::db::{DB, INBOX_ID};
+use crate::models::{Entry, Stream};
+use crate::postprocess;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+/// Request to merge the stream `source` into the stream `target` (both ids).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StreamMerge {
+    pub source: String,
+    pub target: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct MergeReport {
+    /// Ids of the streams merged into another one and deleted.
+    pub merged: Vec<String>,
+    /// Children of the source moved below the target.
+    pub moved: Vec<Stream>,
+    /// Number of entries whose meta was rewritten.
+    pub entries: usize,
+}
+
+fn token(id: &str) -> String {
+    format!("_stream_id_[{}]__", id)
+}
+
+/// Rewrites the id-based `meta` of an entry so that it points to `to` instead of `from`. If the
+/// entry already points to `to`, `from` is simply removed along with one adjacent space, the rest
+/// of `meta` being left untouched.
+pub fn rewrite_meta(meta: &str, from: &str, to: &str) -> String {
+    let (from, to) = (token(from), token(to));
+    if meta.contains(&to) {
+        let mut m = meta.to_string();
+        while let Some(i) = m.find(&from) {
+            let (mut start, mut end) = (i, i + from.len());
+            if m[end..].starts_with(' ') {
+                end += 1;
+            } else if m[..start].ends_with(' ') {
+                start -= 1;
+            }
+            m.replace_range(start..end, "");
+        }
+        m
+    } else {
+        meta.replace(&from, &to)
+    }
+}
+
+impl DB {
+    /// Merges the stream `source` into `target`: entries in `source` are moved to `target`,
+    /// children of `source` are moved below `target` (and merged with the existing children of
+    /// `target` that carry the same name), then `source` is deleted. The `entries`, `streams` and
+    /// `stream_names` trees are updated in a single transaction. The `stats` aggregates are updated
+    /// once it commits, the postprocess pass being reset meanwhile so that it rebuilds them if
+    /// that doesn't happen. The postprocessing pass doesn't run during the merge.
+    pub fn merge_streams(&self, source: &str, target: &str) -> Result<MergeReport> {
+        // The postprocessing pass is held off for the whole merge so that it never writes back
+        // entries read before the merge. Taken before the catalog, as the pass resolves streams.
+        let _postprocessing = self.postprocessing.lock().unwrap();
+        let mut catalog = self.catalog.write().unwrap();
+        let (source, target) = match (catalog.get(source), catalog.get(target)) {
+            (Some(s), Some(t)) => (s.clone(), t.clone()),
+            (None, _) => return Err(anyhow!("Unknown stream: {}", source)),
+            (_, None) => return Err(anyhow!("Unknown stream: {}", target)),
+        };
+        if source.id == target.id {
+            return Err(anyhow!("A stream can't be merged into itself"));
+        }
+        if source.id == INBOX_ID {
+            return Err(anyhow!(
+                "The Inbox stream can't be merged into another stream"
+            ));
+        }
+        if target.name.starts_with(&format!("{}/", source.name)) {
+            return Err(anyhow!(
+                "`{}` can't be merged into its child `{}`",
+                source.name,
+                target.name
+            ));
+        }
+
+        // Streams below `source` whose new name is already taken are merged into the existing
+        // stream, the others are renamed.
+        let mut merges: Vec<(Stream, String)> = vec![(source.clone(), target.id.clone())];
+        let mut moved: Vec<Stream> = vec![];
+        for d in catalog.descendants(&source.name) {
+            let name = format!("{}{}", target.name, &d.name[source.name.len()..]);
+            match catalog.by_name(&name) {
+                Some(t) => merges.push((d.clone(), t.id.clone())),
+                None => moved.push(Stream { name, ..d.clone() }),
+            }
+        }
+
+        // Entries that can't be decoded are quarantined rather than failing the merge.
+        let tokens = merges.iter().map(|(s, _)| token(&s.id)).collect::<Vec<_>>();
+        let mut keys = vec![];
+        for x in self.entries.iter() {
+            let (k, v) = x?;
+            match deserialize::<Entry>(&v) {
+                Ok(e) => {
+                    if tokens.iter().any(|t| e.meta.contains(t)) {
+                        keys.push(k);
+                    }
+                }
+                Err(err) => self.quarantine_record(&self.entries, &k, &v, &format!("{}", err))?,
+            }
+        }
+
+        // The catalog and names of the streams once merged.
+        let mut next = catalog.clone();
+        for s in moved.iter() {
+            next.insert(s.clone());
+        }
+        for (s, _) in merges.iter() {
+            next.remove(&s.id);
+        }
+        let names = merges
+            .iter()
+            .map(|(s, _)| s)
+            .chain(moved.iter())
+            .chain(moved.iter().filter_map(|s| catalog.get(&s.id)))
+            .map(|s| (s.name.clone(), next.by_name(&s.name).map(|s| s.id.clone())))
+            .collect::<Vec<_>>();
+        let postprocessed = postprocess::is_done(&self.meta)?;
+
+        let abort = |err: anyhow::Error| ConflictableTransactionError::Abort(err);
+        (&self.entries, &self.streams, &self.stream_names, &self.meta)
+            .transaction(|(entries, streams, stream_names, meta)| {
+                for k in keys.iter() {
+                    if let Some(v) = entries.get(k)? {
+                        let mut e: Entry = deserialize(&v).map_err(|e| abort(e.into()))?;
+                        for (s, t) in merges.iter() {
+                            e.meta = rewrite_meta(&e.meta, &s.id, t);
+                        }
+                        entries.insert(k, serialize(&e).map_err(|e| abort(e.into()))?)?;
+                    }
+                }
+                for s in moved.iter() {
+                    streams.insert(s.id.as_bytes(), serialize(s).map_err(|e| abort(e.into()))?)?;
+                }
+                for (s, _) in merges.iter() {
+                    streams.remove(s.id.as_bytes())?;
+                }
+                for (name, id) in names.iter() {
+                    match id {
+                        Some(id) => stream_names.insert(name.as_bytes(), id.as_bytes())?,
+                        None => stream_names.remove(name.as_bytes())?,
+                    };
+                }
+                postprocess::reset(meta)?;
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => err.into(),
+            })?;
+        *catalog = next;
+
+        // Entries of merged streams are now in another stream.
+        for k in keys.iter() {
+            if let Some(v) = self.entries.get(k)? {
+                self.index_stats(&deserialize(&v)?)?;
+            }
+        }
+        if postprocessed {
+            postprocess::mark_done(&self.meta)?;
+        }
+
+        Ok(MergeReport {
+            merged: merges.into_iter().map(|(s, _)| s.id).collect(),
+            moved,
+            entries: keys.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_merge_streams() {
+        assert_eq!(
+            "_stream_id_[b]__ foo",
+            rewrite_meta("_stream_id_[a]__ _stream_id_[b]__ foo", "a", "b")
+        );
+        assert_eq!(
+            "_stream_id_[b]__ foo",
+            rewrite_meta("_stream_id_[a]__ foo", "a", "b")
+        );
+
+        assert_eq!(
+            "_stream_id_[b]__ location:\"New  York\"",
+            rewrite_meta(
+                "_stream_id_[b]__ _stream_id_[a]__ location:\"New  York\"",
+                "a",
+                "b"
+            )
+        );
+        assert_eq!(
+            "_stream_id_[b]__",
+            rewrite_meta("_stream_id_[b]__ _stream_id_[a]__", "a", "b")
+        );
+
+        let db = test_db();
+        let entry = |meta: &str| test_entry(&db, meta, "", "");
+        let both = entry("{Books} {Reading} note");
+        let one = entry("{Books/SciFi}");
+        let child = entry("{Books/Essays}");
+        entry("{Reading/Essays}");
+        let books = db.stream_by_name("Books", false).unwrap().unwrap();
+        let reading = db.stream_by_name("Reading", false).unwrap().unwrap();
+
+        // Undecodable entries are quarantined rather than failing the merge.
+        db.entries.insert("1626000000-bad", vec![1, 2, 3]).unwrap();
+
+        let r = db.merge_streams(&books.id, &reading.id).unwrap();
+        assert_eq!(1, db.quarantine.len());
+        assert_eq!(2, r.entries);
+        assert_eq!(2, r.merged.len());
+        assert_eq!(
+            vec!["Reading/SciFi"],
+            r.moved.iter().map(|s| s.name.as_str()).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            "{Reading} note",
+            db.get_entry(&both.id).unwrap().unwrap().meta
+        );
+        assert_eq!(
+            "{Reading/SciFi}",
+            db.get_entry(&one.id).unwrap().unwrap().meta
+        );
+        assert_eq!(
+            "{Reading/Essays}",
+            db.get_entry(&child.id).unwrap().unwrap().meta
+        );
+        assert!(db.stream_by_name("Books", false).unwrap().is_none());
+        assert!(db.stream_by_name("Books/Essays", false).unwrap().is_none());
+        assert_eq!(4, db.list_entries("{Reading}", 0, 10).unwrap().0);
+        assert_eq!(2, db.list_entries("{Reading/Essays}", 0, 10).unwrap().0);
+        assert_eq!(4, db.stats("{Reading}").unwrap().entries);
+        assert!(db.stream_id_by_name("Books").unwrap().is_none());
+        assert_eq!(
+            Some(r.moved[0].id.clone()),
+            db.stream_id_by_name("Reading/SciFi").unwrap()
+        );
+        assert!(postprocess::is_done(&db.meta).unwrap());
+    }
+}
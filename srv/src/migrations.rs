@@ -0,0 +1,490 @@
+use crate::db::{quarantine, SCHEMA_VERSION};
+use crate::models::{Entry, EntryPrev, EntryV2, EntryV4, EntryV6, Stream, StreamPrev};
+use crate::sort;
+use crate::tasks;
+use crate::time;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+/// Key of the schema version in the `meta` tree, stored as a big-endian `u32`.
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A step migrating the database from `version - 1` to `version`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub run: fn(&sled::Db) -> Result<()>,
+}
+
+/// Returns the migration steps, ordered by version. The last one must bring the database to
+/// `SCHEMA_VERSION`.
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: "convert legacy `EntryPrev` and `StreamPrev` records",
+            run: migrate_legacy_records,
+        },
+        Migration {
+            version: 2,
+            description: "index streams by name",
+            run: index_stream_names,
+        },
+        Migration {
+            version: 3,
+            description: "parse `key:value` fields of entries",
+            run: parse_entry_fields,
+        },
+        Migration {
+            version: 4,
+            description: "index the tasks of entries",
+            run: index_entry_tasks,
+        },
+        Migration {
+            version: 5,
+            description: "add `updated` to entries and index them by update time and title",
+            run: add_entry_updated,
+        },
+        Migration {
+            version: 6,
+            description: "index entries by creation time",
+            run: index_entry_created,
+        },
+        Migration {
+            version: 7,
+            description: "add the UTC offset of their author to entries",
+            run: add_entry_tz_offset,
+        },
+        Migration {
+            version: 8,
+            description: "index the pending links between entries by source",
+            run: index_pending_links,
+        },
+    ]
+}
+
+/// Returns the schema version stored in the `meta` tree. Databases without a stored version are
+/// considered at `SCHEMA_VERSION` if they are empty, and at version 0 (records from before
+/// versioning) otherwise.
+pub fn schema_version(db: &sled::Db) -> Result<u32> {
+    match stored_version(&db.open_tree("meta")?)? {
+        Some(v) => Ok(v),
+        None => {
+            if db.open_tree("entries")?.is_empty() && db.open_tree("streams")?.is_empty() {
+                Ok(SCHEMA_VERSION)
+            } else {
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Reads the schema version from the `meta` tree, if any.
+pub fn stored_version(meta: &sled::Tree) -> Result<Option<u32>> {
+    match meta.get(SCHEMA_VERSION_KEY)? {
+        Some(v) => match v.as_ref().try_into() {
+            Ok(b) => Ok(Some(u32::from_be_bytes(b))),
+            Err(_) => Err(anyhow!("Malformed schema version: {:?}", v.as_ref())),
+        },
+        None => Ok(None),
+    }
+}
+
+fn set_schema_version(db: &sled::Db, version: u32) -> Result<()> {
+    let meta = db.open_tree("meta")?;
+    meta.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+    Ok(())
+}
+
+/// Copies the whole database to `<path>.snapshot-v<version>-<secs>` and returns that path.
+fn snapshot(db: &sled::Db, path: &Path, version: u32) -> Result<PathBuf> {
+    let now = time::now();
+    let target = PathBuf::from(format!("{}.snapshot-v{}-{}", path.display(), version, now));
+    let s = sled::open(&target)?;
+    s.import(db.export());
+    s.flush()?;
+    Ok(target)
+}
+
+/// Runs the pending migrations up to `SCHEMA_VERSION`. If `path` is provided, a snapshot of the
+/// database is taken beforehand (see `snapshot`). Returns the resulting schema version.
+pub fn migrate(db: &sled::Db, path: Option<&Path>) -> Result<u32> {
+    let version = schema_version(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "Database schema version {} is newer than supported version {}",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    let pending = migrations()
+        .into_iter()
+        .filter(|m| m.version > version)
+        .collect::<Vec<_>>();
+    if !pending.is_empty() {
+        if let Some(path) = path {
+            let target = snapshot(db, path, version)?;
+            tracing::info!(
+                version,
+                path = target.display().to_string().as_str(),
+                "snapshot"
+            );
+        }
+    }
+
+    for m in pending {
+        tracing::info!(version = m.version, description = m.description, "migrate");
+        (m.run)(db)?;
+        set_schema_version(db, m.version)?;
+        db.flush()?;
+    }
+    set_schema_version(db, SCHEMA_VERSION)?;
+
+    Ok(SCHEMA_VERSION)
+}
+
+/// Version 1: records written before `Entry.id`/`Entry.created` became mandatory and
+/// `Stream.meta` was introduced are converted. Records that can't be decoded either way are
+/// quarantined.
+fn migrate_legacy_records(db: &sled::Db) -> Result<()> {
+    let entries = db.open_tree("entries")?;
+    let streams = db.open_tree("streams")?;
+    let q = db.open_tree("quarantine")?;
+
+    for x in entries.iter() {
+        let (k, v) = x?;
+        if deserialize::<EntryV2>(&v).is_ok() {
+            continue;
+        }
+        match deserialize::<EntryPrev>(&v) {
+            Ok(EntryPrev {
+                id: Some(id),
+                created: Some(created),
+                meta,
+                title,
+                body,
+            }) => {
+                let e = EntryV2 {
+                    id,
+                    created,
+                    meta,
+                    title,
+                    body,
+                };
+                entries.remove(&k)?;
+                entries.insert(e.id.as_bytes(), serialize(&e)?)?;
+            }
+            Ok(_) => quarantine(&q, &entries, &k, &v, "Legacy entry without id or created")?,
+            Err(err) => quarantine(&q, &entries, &k, &v, &format!("{}", err))?,
+        }
+    }
+
+    for x in streams.iter() {
+        let (k, v) = x?;
+        if deserialize::<Stream>(&v).is_ok() {
+            continue;
+        }
+        match deserialize::<StreamPrev>(&v) {
+            Ok(o) => {
+                let s = Stream {
+                    id: o.id,
+                    meta: String::from(""),
+                    name: o.name,
+                };
+                streams.remove(&k)?;
+                streams.insert(s.id.as_bytes(), serialize(&s)?)?;
+            }
+            Err(err) => quarantine(&q, &streams, &k, &v, &format!("{}", err))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 2: builds the `stream_names` tree mapping stream names to their ids. If several
+/// streams share a name, the first one (by id) is indexed.
+fn index_stream_names(db: &sled::Db) -> Result<()> {
+    let streams = db.open_tree("streams")?;
+    let names = db.open_tree("stream_names")?;
+    let q = db.open_tree("quarantine")?;
+
+    names.clear()?;
+    for x in streams.iter() {
+        let (k, v) = x?;
+        let s: Stream = match deserialize(&v) {
+            Ok(s) => s,
+            Err(err) => {
+                quarantine(&q, &streams, &k, &v, &format!("{}", err))?;
+                continue;
+            }
+        };
+        names
+            .compare_and_swap(
+                s.name.as_bytes(),
+                None as Option<&[u8]>,
+                Some(s.id.as_bytes()),
+            )?
+            .ok();
+    }
+
+    Ok(())
+}
+
+/// Version 3: `Entry.fields` is introduced, parsed from the `key:value` fields of `meta`.
+fn parse_entry_fields(db: &sled::Db) -> Result<()> {
+    let entries = db.open_tree("entries")?;
+    let q = db.open_tree("quarantine")?;
+
+    for x in entries.iter() {
+        let (k, v) = x?;
+        // Records already at this layout would also decode as `EntryV2` (trailing bytes are
+        // ignored), so they are checked first.
+        if deserialize::<EntryV4>(&v).is_ok() {
+            continue;
+        }
+        match deserialize::<EntryV2>(&v) {
+            Ok(e) => {
+                let e: EntryV4 = e.into();
+                entries.insert(&k, serialize(&e)?)?;
+            }
+            Err(err) => quarantine(&q, &entries, &k, &v, &format!("{}", err))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 4: builds the `tasks` tree indexing the task items of entries' bodies.
+fn index_entry_tasks(db: &sled::Db) -> Result<()> {
+    let entries = db.open_tree("entries")?;
+    let t = db.open_tree("tasks")?;
+    let q = db.open_tree("quarantine")?;
+
+    t.clear()?;
+    for x in entries.iter() {
+        let (k, v) = x?;
+        let e: EntryV4 = match deserialize(&v) {
+            Ok(e) => e,
+            Err(err) => {
+                quarantine(&q, &entries, &k, &v, &format!("{}", err))?;
+                continue;
+            }
+        };
+        tasks::index(&t, &e.id, &e.body)?;
+    }
+
+    Ok(())
+}
+
+/// Version 5: `Entry.updated` is introduced, initialized to `created`. The `by_updated` and
+/// `titles` indexes used to sort entries are (re)built.
+fn add_entry_updated(db: &sled::Db) -> Result<()> {
+    let entries = db.open_tree("entries")?;
+    let by_updated = db.open_tree("by_updated")?;
+    let titles = db.open_tree("titles")?;
+    let q = db.open_tree("quarantine")?;
+
+    by_updated.clear()?;
+    titles.clear()?;
+    for x in entries.iter() {
+        let (k, v) = x?;
+        let e: EntryV6 = match deserialize::<EntryV6>(&v) {
+            Ok(e) => e,
+            Err(_) => match deserialize::<EntryV4>(&v) {
+                Ok(e) => {
+                    let e: EntryV6 = e.into();
+                    entries.insert(&k, serialize(&e)?)?;
+                    e
+                }
+                Err(err) => {
+                    quarantine(&q, &entries, &k, &v, &format!("{}", err))?;
+                    continue;
+                }
+            },
+        };
+        let e: Entry = e.into();
+        by_updated.insert(sort::updated_key(&e), &[])?;
+        titles.insert(sort::title_key(&e), &[])?;
+    }
+
+    Ok(())
+}
+
+/// Version 6: entries can be backdated while keeping their id, so they are listed by creation
+/// time through the `by_created` index rather than in the order of the `entries` tree.
+fn index_entry_created(db: &sled::Db) -> Result<()> {
+    let entries = db.open_tree("entries")?;
+    let by_created = db.open_tree("by_created")?;
+    let q = db.open_tree("quarantine")?;
+
+    by_created.clear()?;
+    for x in entries.iter() {
+        let (k, v) = x?;
+        let e: Entry = match deserialize::<EntryV6>(&v) {
+            Ok(e) => e.into(),
+            Err(err) => {
+                quarantine(&q, &entries, &k, &v, &format!("{}", err))?;
+                continue;
+            }
+        };
+        by_created.insert(sort::created_key(&e), &[])?;
+    }
+
+    Ok(())
+}
+
+/// Version 7: `Entry.tz_offset` is introduced. Existing entries get the local UTC offset at their
+/// creation time, which is how their dates were computed so far.
+fn add_entry_tz_offset(db: &sled::Db) -> Result<()> {
+    let entries = db.open_tree("entries")?;
+    let q = db.open_tree("quarantine")?;
+
+    for x in entries.iter() {
+        let (k, v) = x?;
+        // Records already at this layout would also decode as `EntryV6` (trailing bytes are
+        // ignored), so they are checked first.
+        if deserialize::<Entry>(&v).is_ok() {
+            continue;
+        }
+        match deserialize::<EntryV6>(&v) {
+            Ok(e) => {
+                let e: Entry = e.into();
+                entries.insert(&k, serialize(&e)?)?;
+            }
+            Err(err) => quarantine(&q, &entries, &k, &v, &format!("{}", err))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 8: pending links (see `links`) are also keyed by source, as
+/// `pending_src\0<source>\0<text>`, so that they are cleaned up without scanning them all.
+fn index_pending_links(db: &sled::Db) -> Result<()> {
+    let links = db.open_tree("links")?;
+
+    for x in links.scan_prefix(b"pending\0") {
+        let (k, _) = x?;
+        let parts = String::from_utf8_lossy(&k)
+            .split('\0')
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        if let [_, text, source] = &parts[..] {
+            links.insert(format!("pending_src\0{}\0{}", source, text), &[])?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DB;
+
+    #[test]
+    fn test_migrations_ordered() {
+        let m = migrations();
+        for (i, s) in m.iter().enumerate() {
+            assert_eq!(i as u32 + 1, s.version);
+        }
+        assert_eq!(SCHEMA_VERSION, m.last().unwrap().version);
+    }
+
+    #[test]
+    fn test_migrations_quarantine() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let entries = db.open_tree("entries").unwrap();
+        let streams = db.open_tree("streams").unwrap();
+        let e: EntryV6 = EntryV4 {
+            id: String::from("1626000000-foo"),
+            created: 1626000000,
+            meta: String::from(""),
+            fields: Default::default(),
+            title: String::from("Foo"),
+            body: String::from("- [ ] task"),
+        }
+        .into();
+        entries
+            .insert("1626000000-foo", serialize(&e).unwrap())
+            .unwrap();
+        entries.insert("1626000000-bar", vec![1, 2, 3]).unwrap();
+        streams.insert("1626000000-baz", vec![1, 2, 3]).unwrap();
+
+        // Undecodable records are quarantined without failing the migration.
+        index_stream_names(&db).unwrap();
+        index_entry_tasks(&db).unwrap();
+        entries.insert("1626000000-qux", vec![1, 2, 3]).unwrap();
+        index_entry_created(&db).unwrap();
+        assert_eq!(1, entries.len());
+        assert!(streams.is_empty());
+        assert_eq!(3, db.open_tree("quarantine").unwrap().len());
+        assert_eq!(1, db.open_tree("by_created").unwrap().len());
+        assert_eq!(1, db.open_tree("tasks").unwrap().len());
+    }
+
+    #[test]
+    fn test_migrate_legacy_records() {
+        let path = std::env::temp_dir().join(format!("dump-test-{}", nanoid::nanoid!()));
+        {
+            let db = sled::open(&path).unwrap();
+            let entries = db.open_tree("entries").unwrap();
+            let streams = db.open_tree("streams").unwrap();
+            let e = EntryPrev {
+                id: Some(String::from("1626000000-foo")),
+                created: Some(1626000000),
+                meta: String::from("_stream_id_[1626000000-bar]__ mood:4.0"),
+                title: String::from("Foo"),
+                body: String::from(""),
+            };
+            entries
+                .insert("1626000000-foo", serialize(&e).unwrap())
+                .unwrap();
+            entries.insert("1626000000-baz", vec![1, 2, 3]).unwrap();
+            let s = StreamPrev {
+                id: String::from("1626000000-bar"),
+                name: String::from("Bar"),
+            };
+            streams
+                .insert("1626000000-bar", serialize(&s).unwrap())
+                .unwrap();
+            db.flush().unwrap();
+        }
+
+        let db = DB::new(&path).unwrap();
+        assert_eq!(SCHEMA_VERSION, db.schema_version().unwrap());
+
+        let (total, entries) = db.list_entries("{Bar}", 0, 10).unwrap();
+        assert_eq!(1, total);
+        assert_eq!("{Bar} mood:4", entries[0].meta);
+        assert_eq!(
+            Some(&crate::models::Field::Number(4.0)),
+            entries[0].fields.get("mood")
+        );
+        assert_eq!(1, db.quarantine.len());
+        assert_eq!(
+            Some(String::from("1626000000-bar")),
+            db.stream_id_by_name("Bar").unwrap()
+        );
+
+        let snapshots = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_name().to_string_lossy().starts_with(&format!(
+                    "{}.snapshot-v0-",
+                    path.file_name().unwrap().to_string_lossy()
+                ))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(1, snapshots.len());
+        let snapshot = sled::open(snapshots[0].path()).unwrap();
+        assert_eq!(2, snapshot.open_tree("entries").unwrap().len());
+
+        std::fs::remove_dir_all(snapshots[0].path()).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
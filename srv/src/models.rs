@@ -1,11 +1,18 @@
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct EntryCreation {
     pub meta: String,
     pub title: String,
     pub body: String,
+    /// Creation time of the entry, now if not set.
+    #[serde(default)]
+    pub created: Option<u64>,
+    /// UTC offset of the author, the local one if not set.
+    #[serde(default)]
+    pub tz_offset: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -17,13 +24,131 @@ pub struct EntryPrev {
     pub body: String,
 }
 
+/// Reference to an entry by id, for requests that only need the id.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EntryRef {
+    pub id: String,
+}
+
+/// Layout of `Entry` up to schema version 2, see `migrations`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EntryV2 {
+    pub id: String,
+    pub created: u64,
+    pub meta: String,
+    pub title: String,
+    pub body: String,
+}
+
+/// Typed value of a `key:value` field of an entry's meta, see `fields`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Number(f64),
+    /// A date formatted as `YYYY-MM-DD`.
+    Date(String),
+    /// A person, without the leading `@`.
+    Person(String),
+    Text(String),
+}
+
+/// Layout of `Entry` for schema versions 3 and 4, see `migrations`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EntryV4 {
+    pub id: String,
+    pub created: u64,
+    pub meta: String,
+    pub title: String,
+    pub body: String,
+    pub fields: BTreeMap<String, Field>,
+}
+
+/// Layout of `Entry` for schema versions 5 and 6, see `migrations`.
 #[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EntryV6 {
+    pub id: String,
+    pub created: u64,
+    pub meta: String,
+    pub title: String,
+    pub body: String,
+    pub fields: BTreeMap<String, Field>,
+    pub updated: u64,
+}
+
+// New fields of `Entry` are appended so that records at a previous layout fail to decode as
+// `Entry` instead of being misread, see `migrations`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Entry {
     pub id: String,
     pub created: u64,
     pub meta: String,
     pub title: String,
     pub body: String,
+    /// Fields parsed from `meta` when the entry is inserted.
+    #[serde(default)]
+    pub fields: BTreeMap<String, Field>,
+    /// Time of the last change to the entry's title, meta or body, maintained by `insert_entry`.
+    #[serde(default)]
+    pub updated: u64,
+    /// UTC offset of the author when the entry was created, in seconds (see `time`). Set by
+    /// `insert_entry` to the local one if missing.
+    #[serde(default)]
+    pub tz_offset: Option<i32>,
+}
+
+impl From<EntryV2> for EntryV4 {
+    fn from(e: EntryV2) -> Self {
+        let (meta, fields) = crate::fields::parse(&e.meta, crate::time::local_date(e.created));
+        EntryV4 {
+            id: e.id,
+            created: e.created,
+            meta,
+            title: e.title,
+            body: e.body,
+            fields,
+        }
+    }
+}
+
+impl From<EntryV4> for EntryV6 {
+    fn from(e: EntryV4) -> Self {
+        EntryV6 {
+            id: e.id,
+            created: e.created,
+            meta: e.meta,
+            title: e.title,
+            body: e.body,
+            fields: e.fields,
+            updated: e.created,
+        }
+    }
+}
+
+impl From<EntryV6> for Entry {
+    fn from(e: EntryV6) -> Self {
+        Entry {
+            id: e.id,
+            created: e.created,
+            meta: e.meta,
+            title: e.title,
+            body: e.body,
+            fields: e.fields,
+            updated: e.updated,
+            tz_offset: Some(crate::time::local_offset(e.created)),
+        }
+    }
+}
+
+impl From<EntryV4> for Entry {
+    fn from(e: EntryV4) -> Self {
+        EntryV6::from(e).into()
+    }
+}
+
+impl From<EntryV2> for Entry {
+    fn from(e: EntryV2) -> Self {
+        EntryV4::from(e).into()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -32,6 +157,17 @@ pub struct StreamPrev {
     pub name: String,
 }
 
+/// A record that could not be decoded, kept aside in the `quarantine` tree instead of being
+/// dropped. `tree` and `key` record where it was found and `data` its raw bytes.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Quarantined {
+    pub tree: String,
+    pub key: String,
+    pub error: String,
+    pub quarantined: u64,
+    pub data: Vec<u8>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Eq)]
 pub struct Stream {
     pub id: String,
@@ -39,14 +175,85 @@ pub struct Stream {
     pub name: String,
 }
 
+/// Version of the `StreamAttributes` layout stored in `Stream.meta`.
+pub const STREAM_ATTRIBUTES_VERSION: u32 = 1;
+
+/// Attributes of a stream, stored as JSON in `Stream.meta` (an empty `meta` stands for the
+/// default attributes).
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct StreamAttributes {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub pinned: bool,
+    /// Position of the stream among the pinned (or unpinned) streams, streams without one
+    /// come last.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<i64>,
+    /// Name of the template of the stream's daily entries, see `templates`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+impl StreamAttributes {
+    pub fn parse(meta: &str) -> Result<Self, serde_json::Error> {
+        if meta.trim().is_empty() {
+            return Ok(StreamAttributes::default());
+        }
+        serde_json::from_str(meta)
+    }
+
+    /// Serializes the attributes for `Stream.meta`, stamped with the current version. Default
+    /// attributes are stored as an empty string.
+    pub fn to_meta(&self) -> String {
+        if *self == StreamAttributes::default() {
+            return String::from("");
+        }
+        serde_json::to_string(&StreamAttributes {
+            version: STREAM_ATTRIBUTES_VERSION,
+            ..self.clone()
+        })
+        .unwrap()
+    }
+}
+
+/// Request to rename the stream `id` (and the streams below it) to `name`. If `merge` is set and
+/// a stream named `name` exists, the stream is merged into it instead of failing.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StreamRename {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub merge: bool,
+}
+
+/// Returns the names of the streams in the hierarchy of `name`, starting from the root and ending
+/// with `name` itself (`Foo/Bar` gives `["Foo", "Foo/Bar"]`).
+pub fn parent_names(name: &str) -> Vec<String> {
+    let mut parents: Vec<String> = vec![];
+    let components = name.split('/').collect::<Vec<_>>();
+    for c in 0..components.len() {
+        parents.push(components[0..=c].join("/"))
+    }
+    parents
+}
+
 impl Stream {
     pub fn parent_names(&self) -> Vec<String> {
-        let mut parents: Vec<String> = vec![];
-        let components = self.name.split("/").collect::<Vec<_>>();
-        for c in 0..components.len() {
-            parents.push(String::from(&components[0..=c].join("/")))
-        }
-        parents
+        parent_names(&self.name)
+    }
+
+    /// Returns the attributes stored in `meta`, or the default ones if they can't be parsed.
+    pub fn attributes(&self) -> StreamAttributes {
+        StreamAttributes::parse(&self.meta).unwrap_or_default()
     }
 }
 
@@ -67,7 +274,16 @@ impl Ord for Stream {
         } else if other.name == "Inbox" {
             Ordering::Greater
         } else {
-            self.name.cmp(&other.name)
+            let (a, b) = (self.attributes(), other.attributes());
+            b.pinned
+                .cmp(&a.pinned)
+                .then_with(|| match (a.sort, b.sort) {
+                    (Some(x), Some(y)) => x.cmp(&y),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                })
+                .then_with(|| self.name.cmp(&other.name))
         }
     }
 }
@@ -119,4 +335,53 @@ mod tests {
         };
         assert_eq!(vec!["", "/Foo", "/Foo/"], s.parent_names());
     }
+
+    #[test]
+    fn test_stream_attributes() {
+        let stream = |name: &str, a: StreamAttributes| Stream {
+            id: String::from(name),
+            meta: a.to_meta(),
+            name: String::from(name),
+        };
+        assert_eq!("", StreamAttributes::default().to_meta());
+
+        let a = StreamAttributes {
+            color: Some(String::from("#ff0000")),
+            pinned: true,
+            ..StreamAttributes::default()
+        };
+        let s = stream("Foo", a.clone());
+        assert_eq!(
+            r##"{"version":1,"color":"#ff0000","archived":false,"pinned":true}"##,
+            s.meta
+        );
+        assert_eq!(STREAM_ATTRIBUTES_VERSION, s.attributes().version);
+        assert!(StreamAttributes::parse("{").is_err());
+
+        let mut streams = [
+            stream("Zed", StreamAttributes::default()),
+            stream(
+                "Bar",
+                StreamAttributes {
+                    sort: Some(2),
+                    ..StreamAttributes::default()
+                },
+            ),
+            stream(
+                "Acme",
+                StreamAttributes {
+                    sort: Some(1),
+                    ..StreamAttributes::default()
+                },
+            ),
+            stream("Foo", a),
+            stream("Inbox", StreamAttributes::default()),
+            stream("Alpha", StreamAttributes::default()),
+        ];
+        streams.sort();
+        assert_eq!(
+            vec!["Inbox", "Foo", "Acme", "Bar", "Alpha", "Zed"],
+            streams.iter().map(|s| s.name.as_str()).collect::<Vec<_>>()
+        );
+    }
 }
@@ -0,0 +1,250 @@
+use crate::db::DB;
+use crate::models::Entry;
+use anyhow::Result;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use std::convert::TryInto;
+
+/// Version of the postprocessing applied to all entries. Bump it to have all entries go through
+/// `insert_entry` again (eg: when `preprocess_meta` changes).
+/// - 2: index links between entries (see `links`).
+/// - 3: aggregate stats of entries (see `stats`).
+/// - 4: index terms of entries (see `related`).
+/// - 5: index fields of entries (see `fields`).
+/// - 6: mark the entries in several streams in the `stats` aggregates.
+/// - 7: key these marks by stream.
+pub const POSTPROCESS_VERSION: u32 = 7;
+
+/// Number of entries processed per batch by `DB::postprocess_in_background`.
+const BACKGROUND_BATCH: usize = 100;
+
+const VERSION_KEY: &str = "postprocess_version";
+const STATE_KEY: &str = "postprocess_state";
+
+/// State of an ongoing pass, persisted in the `meta` tree so that it can be resumed.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+struct State {
+    cursor: Option<String>,
+    processed: usize,
+    total: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PostprocessOptions {
+    /// Maximum number of entries processed by this call.
+    pub batch: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PostprocessProgress {
+    pub version: u32,
+    pub processed: usize,
+    pub total: usize,
+    pub done: bool,
+}
+
+/// Records that entries are postprocessed at `POSTPROCESS_VERSION`.
+pub fn mark_done(meta: &sled::Tree) -> Result<()> {
+    meta.insert(VERSION_KEY, &POSTPROCESS_VERSION.to_be_bytes())?;
+    meta.remove(STATE_KEY)?;
+    Ok(())
+}
+
+/// Returns whether entries are postprocessed at `POSTPROCESS_VERSION`.
+pub fn is_done(meta: &sled::Tree) -> Result<bool> {
+    Ok(stored_version(meta)? >= POSTPROCESS_VERSION)
+}
+
+/// Has the next pass start over, as part of a transaction whose effects on the indexes are only
+/// applied once it commits (see `DB::merge_streams`): until `mark_done` is called, they are
+/// rebuilt by the pass.
+pub fn reset(meta: &TransactionalTree) -> Result<(), ConflictableTransactionError<anyhow::Error>> {
+    meta.remove(VERSION_KEY)?;
+    meta.remove(STATE_KEY)?;
+    Ok(())
+}
+
+/// Returns the stored postprocess version, 0 if it is missing or malformed so that the pass runs
+/// again.
+fn stored_version(meta: &sled::Tree) -> Result<u32> {
+    match meta.get(VERSION_KEY)? {
+        Some(v) => Ok(v.as_ref().try_into().map(u32::from_be_bytes).unwrap_or(0)),
+        None => Ok(0),
+    }
+}
+
+impl DB {
+    /// Runs the postprocessing pass on at most `batch` entries: entries are reinserted through
+    /// `rewrite_entry` (which resolves their streams, and skips the entries written since they
+    /// were read) and the ones that can't be decoded are
+    /// quarantined. The pass runs once per `POSTPROCESS_VERSION` and is resumable: its state is
+    /// persisted after each entry. `progress` is called after each entry. Concurrent calls (eg:
+    /// with `postprocess_in_background`) wait for each other.
+    pub fn postprocess(
+        &self,
+        batch: usize,
+        progress: &mut dyn FnMut(&PostprocessProgress),
+    ) -> Result<PostprocessProgress> {
+        let _guard = self.postprocessing.lock().unwrap();
+        if is_done(&self.meta)? {
+            return Ok(PostprocessProgress {
+                version: POSTPROCESS_VERSION,
+                processed: 0,
+                total: 0,
+                done: true,
+            });
+        }
+
+        let mut state: State = match self.meta.get(STATE_KEY)? {
+            Some(v) => deserialize(&v)?,
+            None => State {
+                total: self.entries.len(),
+                ..State::default()
+            },
+        };
+
+        let iter = match &state.cursor {
+            Some(c) => self.entries.range::<&[u8], _>((
+                std::ops::Bound::Excluded(c.as_bytes()),
+                std::ops::Bound::Unbounded,
+            )),
+            None => self.entries.iter(),
+        };
+
+        let mut done = true;
+        for (count, x) in iter.enumerate() {
+            if count >= batch {
+                done = false;
+                break;
+            }
+            let (k, v) = x?;
+            match deserialize::<Entry>(&v) {
+                // Skipped if the entry was written since it was read, see `rewrite_entry`.
+                Ok(e) => {
+                    self.rewrite_entry(&e, &v)?;
+                }
+                Err(err) => self.quarantine_record(&self.entries, &k, &v, &format!("{}", err))?,
+            }
+
+            state.processed += 1;
+            state.total = std::cmp::max(state.total, state.processed);
+            state.cursor = Some(String::from_utf8_lossy(&k).to_string());
+            self.meta.insert(STATE_KEY, serialize(&state)?)?;
+
+            progress(&PostprocessProgress {
+                version: POSTPROCESS_VERSION,
+                processed: state.processed,
+                total: state.total,
+                done: false,
+            });
+        }
+
+        if done {
+            mark_done(&self.meta)?;
+        }
+
+        Ok(PostprocessProgress {
+            version: POSTPROCESS_VERSION,
+            processed: state.processed,
+            total: state.total,
+            done,
+        })
+    }
+
+    /// Runs the postprocessing pass, if pending, from a background thread in batches of
+    /// `BACKGROUND_BATCH` entries, so that the indexes of an upgraded database get built without
+    /// delaying its opening nor blocking other calls to `postprocess` for long.
+    pub fn postprocess_in_background(&self) -> Result<Option<std::thread::JoinHandle<()>>> {
+        if is_done(&self.meta)? {
+            return Ok(None);
+        }
+        let db = self.clone();
+        let handle = std::thread::Builder::new()
+            .name(String::from("postprocess"))
+            .spawn(move || loop {
+                match db.postprocess(BACKGROUND_BATCH, &mut |_| {}) {
+                    Ok(p) if p.done => {
+                        tracing::info!(processed = p.processed, "postprocess");
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(err) => {
+                        tracing::error!(error = format!("{}", err).as_str(), "postprocess");
+                        break;
+                    }
+                }
+            })?;
+        Ok(Some(handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn test_postprocess() {
+        let db = test_db();
+        assert!(db.postprocess(10, &mut |_| {}).unwrap().done);
+
+        // Simulate entries written before streams were resolved in `meta`.
+        db.meta.remove(VERSION_KEY).unwrap();
+        for id in ["1626000000-a", "1626000001-b"] {
+            let e = Entry {
+                id: String::from(id),
+                created: 1626000000,
+                meta: String::from("{Foo}"),
+                title: String::from(""),
+                body: String::from(""),
+                ..Entry::default()
+            };
+            db.entries.insert(id, serialize(&e).unwrap()).unwrap();
+        }
+        db.entries.insert("1626000002-c", vec![1, 2]).unwrap();
+
+        let mut calls = 0;
+        let p = db.postprocess(2, &mut |_| calls += 1).unwrap();
+        assert_eq!((2, 3, false), (p.processed, p.total, p.done));
+        assert_eq!(2, calls);
+
+        let p = db.postprocess(2, &mut |_| {}).unwrap();
+        assert_eq!((3, 3, true), (p.processed, p.total, p.done));
+        assert_eq!(POSTPROCESS_VERSION, stored_version(&db.meta).unwrap());
+
+        assert_eq!(2, db.list_entries("{Foo}", 0, 10).unwrap().0);
+        assert_eq!(1, db.quarantine.len());
+        assert!(db.postprocess(2, &mut |_| {}).unwrap().done);
+
+        // The pass also runs from the background.
+        db.meta.remove(VERSION_KEY).unwrap();
+        db.postprocess_in_background()
+            .unwrap()
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(is_done(&db.meta).unwrap());
+        assert!(db.postprocess_in_background().unwrap().is_none());
+
+        // An entry updated between its read by the pass and its write back keeps the update.
+        let v = db.entries.get("1626000000-a").unwrap().unwrap();
+        let stale: Entry = deserialize(&v).unwrap();
+        db.insert_entry(&Entry {
+            body: String::from("edited"),
+            ..stale.clone()
+        })
+        .unwrap();
+        assert!(!db.rewrite_entry(&stale, &v).unwrap());
+        let e = db
+            .get_entry(&String::from("1626000000-a"))
+            .unwrap()
+            .unwrap();
+        assert_eq!("edited", e.body);
+        assert_eq!(1, db.list_entries("edited", 0, 10).unwrap().0);
+
+        // A malformed version has the pass run again.
+        db.meta.insert(VERSION_KEY, &[1]).unwrap();
+        assert!(!is_done(&db.meta).unwrap());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::db::{clean_stream_names, extract_stream_names};
+use crate::fields::parse_value;
+use crate::models::Entry;
+use crate::time::Bucket;
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A filter on the fields of entries (`mood>3`, `due<today`, `location:Paris`) or on their date
+/// (`created:this-week`, `created>=2026-10`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub key: String,
+    pub op: Op,
+    pub value: String,
+}
+
+/// A parsed `list_entries` query: stream names (`{Work}`), field filters, `is:` flags
+/// (`is:task`, `is:open-task`, other `is:` tokens being text) and the remaining text matched
+/// against titles and bodies. A `~` suffix makes the query fuzzy (see `fuzzy`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub streams: Vec<String>,
+    pub filters: Vec<Filter>,
+    pub is: Vec<String>,
+    pub text: String,
+    pub fuzzy: bool,
+}
+
+/// Values of the `is:` flags, see `DB::entry_matcher`.
+pub const IS_FLAGS: [&str; 2] = ["task", "open-task"];
+
+lazy_static! {
+    static ref FILTER_RE: Regex = Regex::new(
+        r#"\{[^\{\}]*\}|(?P<pre>^|\s)(?P<key>[a-z][a-z0-9_]*)(?P<op>>=|<=|!=|>|<|=|:)(?P<value>"[^"]*"|[^\s"\{\}/][^\s"\{\}]*)"#
+    )
+    .unwrap();
+}
+
+/// Parses `query`. `key:value` (or `key<value`, ...) tokens are filters only if `known(key)`, the
+/// key is `created` or the value is quoted (`todo:"fix"`), so that searching `re:meeting` still
+/// matches text.
+pub fn parse(query: &str, known: &dyn Fn(&str) -> bool) -> Query {
+    let mut filters = vec![];
+    let mut is = vec![];
+    let rest = FILTER_RE.replace_all(query, |c: &Captures| match c.name("key") {
+        Some(key) if key.as_str() == "is" && &c["op"] == ":" && IS_FLAGS.contains(&&c["value"]) => {
+            is.push(c["value"].to_string());
+            c["pre"].to_string()
+        }
+        Some(key)
+            if key.as_str() == "created" || known(key.as_str()) || c["value"].starts_with('"') =>
+        {
+            let op = match &c["op"] {
+                ">=" => Op::Ge,
+                "<=" => Op::Le,
+                "!=" => Op::Ne,
+                ">" => Op::Gt,
+                "<" => Op::Lt,
+                _ => Op::Eq,
+            };
+            filters.push(Filter {
+                key: key.as_str().to_string(),
+                op,
+                value: c["value"].to_string(),
+            });
+            c["pre"].to_string()
+        }
+        _ => c[0].to_string(),
+    });
+    let text = clean_stream_names(&rest)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let fuzzy = text.ends_with('~');
+    Query {
+        streams: extract_stream_names(query),
+        filters,
+        is,
+        text: text.trim_end_matches('~').trim_end().to_string(),
+        fuzzy,
+    }
+}
+
+impl Filter {
+    /// Whether `entry` passes the filter. Relative dates in the filter value are resolved against
+    /// `today`. Entries without the field only pass `!=` filters.
+    pub fn matches(&self, entry: &Entry, today: NaiveDate) -> bool {
+        if self.key == "created" && !entry.fields.contains_key("created") {
+            return self.matches_date(entry.date(), today);
+        }
+        let field = match entry.fields.get(&self.key) {
+            Some(f) => f,
+            None => return self.op == Op::Ne,
+        };
+        let ord = field.compare(&parse_value(&self.value, today));
+        match self.op {
+            Op::Eq => ord == Some(Ordering::Equal),
+            Op::Ne => ord != Some(Ordering::Equal),
+            Op::Lt => ord == Some(Ordering::Less),
+            Op::Le => matches!(ord, Some(Ordering::Less) | Some(Ordering::Equal)),
+            Op::Gt => ord == Some(Ordering::Greater),
+            Op::Ge => matches!(ord, Some(Ordering::Greater) | Some(Ordering::Equal)),
+        }
+    }
+}
+
+impl Filter {
+    /// Compares `date` to the day, week or month of the filter value (see `Bucket::parse`):
+    /// `created:this-week` matches the dates of the current week, `created<2026-10` the ones
+    /// before October 2026. Buckets ending past the last representable date have no upper bound.
+    fn matches_date(&self, date: NaiveDate, today: NaiveDate) -> bool {
+        let (bucket, start) = match Bucket::parse(&self.value, today) {
+            Some(b) => b,
+            None => return self.op == Op::Ne,
+        };
+        let before_end = match bucket.next(start) {
+            Some(end) => date < end,
+            None => true,
+        };
+        match self.op {
+            Op::Eq => date >= start && before_end,
+            Op::Ne => date < start || !before_end,
+            Op::Lt => date < start,
+            Op::Le => before_end,
+            Op::Gt => !before_end,
+            Op::Ge => date >= start,
+        }
+    }
+}
+
+impl Query {
+    pub fn matches_fields(&self, entry: &Entry, today: NaiveDate) -> bool {
+        self.filters.iter().all(|f| f.matches(entry, today))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+    use crate::fields;
+
+    fn parse_all(query: &str) -> Query {
+        parse(query, &|_| true)
+    }
+
+    #[test]
+    fn test_query() {
+        let q =
+            parse_all("{Work} mood>3 due<=today location:\"New York\" is:open-task standup notes");
+        assert_eq!(vec!["Work"], q.streams);
+        assert_eq!(vec!["open-task"], q.is);
+        assert_eq!("standup notes", q.text);
+        assert_eq!(
+            vec![(Op::Gt, "3"), (Op::Le, "today"), (Op::Eq, "\"New York\"")],
+            q.filters
+                .iter()
+                .map(|f| (f.op, f.value.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert!(!q.fuzzy);
+        let u = parse("re:meeting todo:\"fix\" mood>3", &|k| k == "mood");
+        assert_eq!("re:meeting", u.text);
+        assert_eq!(
+            vec!["todo", "mood"],
+            u.filters.iter().map(|f| f.key.as_str()).collect::<Vec<_>>()
+        );
+        let i = parse("is:foo is:task", &|_| false);
+        assert_eq!("is:foo", i.text);
+        assert_eq!(vec!["task"], i.is);
+        let f = parse_all("{Work} standup notez~");
+        assert_eq!(("standup notez", true), (f.text.as_str(), f.fuzzy));
+
+        let today = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let entry = |meta: &str| {
+            let (meta, fields) = fields::parse(meta, today);
+            Entry {
+                meta,
+                fields,
+                ..Entry::default()
+            }
+        };
+        let e = entry("mood:4 due:2026-10-18 location:\"new york\"");
+        assert!(q.matches_fields(&e, today));
+        assert!(!q.matches_fields(&entry("mood:3 due:2026-10-18"), today));
+        assert!(!parse_all("due>today").matches_fields(&e, today));
+        assert!(parse_all("mood!=5 weather!=rain").matches_fields(&e, today));
+        assert!(!parse_all("mood>high").matches_fields(&e, today));
+
+        // Dates are bucketed in the time zone of the author: 2026-10-12 23:00 in UTC-2.
+        let e = Entry {
+            created: 1791853200,
+            tz_offset: Some(-2 * 3600),
+            ..Entry::default()
+        };
+        assert!(parse_all("created:last-week").matches_fields(&e, today));
+        assert!(parse_all("created:2026-10-12 created<this-week").matches_fields(&e, today));
+        assert!(parse_all("created>=2026-10 created<=2026-W42").matches_fields(&e, today));
+        assert!(!parse_all("created:2026-10-13").matches_fields(&e, today));
+        assert!(!parse_all("created:soon").matches_fields(&e, today));
+        // The last month has no upper bound rather than failing.
+        let last = Bucket::Month.label(NaiveDate::MAX);
+        assert!(!parse_all(&format!("created:{}", last)).matches_fields(&e, today));
+        assert!(parse_all(&format!("created<={}", last)).matches_fields(&e, today));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let db = test_db();
+        let entry = |meta: &str, body: &str| test_entry(&db, meta, "", body);
+        entry("", "todo:fix the build");
+        entry("mood:4", "");
+
+        // Unknown keys are searched as text, known ones filter.
+        assert_eq!(1, db.list_entries("todo:fix", 0, 10).unwrap().0);
+        assert_eq!(1, db.list_entries("mood>3", 0, 10).unwrap().0);
+        assert_eq!(0, db.list_entries("todo:\"fix\"", 0, 10).unwrap().0);
+    }
+}
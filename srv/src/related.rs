@@ -0,0 +1,253 @@
+use crate::db::{key, update_record, DB};
+use crate::models::Entry;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// The `terms` tree is an inverted index of the terms of the titles and bodies of entries, with
+// `\0` separated keys:
+// - `t\0<term>\0<entry id>`: number of occurrences of the term in the entry.
+// - `d\0<term>`: number of entries containing the term.
+// - `e\0<entry id>`: terms of the entry with their number of occurrences, removed from the index
+//   when the entry is updated or deleted.
+// - `n`: number of indexed entries.
+// Related entries are ranked by cosine similarity of TF-IDF vectors, computed at query time from
+// the current document frequencies.
+
+/// Words too common to tell entries apart.
+const STOP_WORDS: &[&str] = &[
+    "about", "after", "all", "also", "and", "any", "are", "been", "before", "but", "can", "could",
+    "did", "does", "for", "from", "had", "has", "have", "her", "his", "how", "into", "its", "just",
+    "more", "not", "now", "our", "out", "she", "should", "some", "than", "that", "the", "their",
+    "them", "then", "there", "these", "they", "this", "those", "was", "were", "what", "when",
+    "which", "who", "will", "with", "would", "you", "your",
+];
+
+/// Request for the `k` entries most similar to the entry `id`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RelatedRequest {
+    pub id: String,
+    pub k: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RelatedEntry {
+    /// Cosine similarity with the entry, between 0 and 1.
+    pub score: f64,
+    pub entry: Entry,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RelatedList {
+    pub entries: Vec<RelatedEntry>,
+}
+
+/// Returns the terms of `text` with their number of occurrences: lowercased words of at least 3
+/// characters, numbers and `STOP_WORDS` excluded.
+pub fn terms(text: &str) -> BTreeMap<String, u32> {
+    let mut out = BTreeMap::new();
+    for w in text
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|w| w.to_lowercase())
+    {
+        if w.chars().count() < 3
+            || w.chars().all(|c| c.is_numeric())
+            || STOP_WORDS.contains(&w.as_str())
+        {
+            continue;
+        }
+        *out.entry(w).or_insert(0) += 1;
+    }
+    out
+}
+
+/// TF-IDF weight of a term occurring `tf` times in an entry and in `df` of the `n` entries.
+fn weight(tf: u32, df: u64, n: u64) -> f64 {
+    if tf == 0 || df == 0 || df >= n {
+        return 0.0;
+    }
+    (1.0 + (tf as f64).ln()) * (n as f64 / df as f64).ln()
+}
+
+impl DB {
+    /// Adds `delta` to the counter `k` atomically, removing it when it drops to 0.
+    fn add_count(&self, k: &[u8], delta: i64) -> Result<()> {
+        update_record(&self.terms, k, |c: Option<u64>| {
+            match (c.unwrap_or(0) as i64 + delta).max(0) as u64 {
+                0 => None,
+                c => Some(c),
+            }
+        })
+    }
+
+    fn count(&self, k: &[u8]) -> Result<u64> {
+        match self.terms.get(k)? {
+            Some(v) => Ok(deserialize(&v)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Updates the `terms` index for `entry`.
+    pub(crate) fn index_terms(&self, entry: &Entry) -> Result<()> {
+        self.unindex_terms(&entry.id)?;
+        let t = terms(&format!("{}\n{}", entry.title, entry.body));
+        for (term, tf) in t.iter() {
+            self.terms
+                .insert(key(&["t", term, &entry.id]), serialize(tf)?)?;
+            self.add_count(&key(&["d", term]), 1)?;
+        }
+        self.terms.insert(key(&["e", &entry.id]), serialize(&t)?)?;
+        self.add_count(b"n", 1)?;
+        Ok(())
+    }
+
+    pub(crate) fn unindex_terms(&self, id: &str) -> Result<()> {
+        if let Some(v) = self.terms.remove(key(&["e", id]))? {
+            let t: BTreeMap<String, u32> = deserialize(&v)?;
+            for term in t.keys() {
+                self.terms.remove(key(&["t", term, id]))?;
+                self.add_count(&key(&["d", term]), -1)?;
+            }
+            self.add_count(b"n", -1)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the `k` entries most similar to the entry `id`, most similar first. Entries
+    /// sharing no significant term with it are never returned.
+    pub fn related_entries(&self, id: &str, k: usize) -> Result<Vec<RelatedEntry>> {
+        let t: BTreeMap<String, u32> = match self.terms.get(key(&["e", id]))? {
+            Some(v) => deserialize(&v)?,
+            None => return Err(anyhow!("Unknown entry: {}", id)),
+        };
+        let n = self.count(b"n")?;
+        let mut df: HashMap<String, u64> = HashMap::new();
+        let mut weight_of = |term: &str, tf: u32| -> Result<f64> {
+            let d = match df.get(term) {
+                Some(d) => *d,
+                None => {
+                    let d = self.count(&key(&["d", term]))?;
+                    df.insert(term.to_string(), d);
+                    d
+                }
+            };
+            Ok(weight(tf, d, n))
+        };
+
+        let mut norm = 0.0;
+        let mut dots: HashMap<String, f64> = HashMap::new();
+        for (term, tf) in t.iter() {
+            let w = weight_of(term, *tf)?;
+            if w == 0.0 {
+                continue;
+            }
+            norm += w * w;
+            let mut prefix = key(&["t", term]);
+            prefix.push(0);
+            for x in self.terms.scan_prefix(&prefix) {
+                let (k, v) = x?;
+                let other = String::from_utf8_lossy(&k[prefix.len()..]).to_string();
+                if other != id {
+                    *dots.entry(other).or_insert(0.0) += w * weight_of(term, deserialize(&v)?)?;
+                }
+            }
+        }
+        if norm == 0.0 {
+            return Ok(vec![]);
+        }
+
+        let mut scores = vec![];
+        for (other, dot) in dots.into_iter() {
+            let o: BTreeMap<String, u32> = match self.terms.get(key(&["e", &other]))? {
+                Some(v) => deserialize(&v)?,
+                None => continue,
+            };
+            let mut other_norm = 0.0;
+            for (term, tf) in o.iter() {
+                let w = weight_of(term, *tf)?;
+                other_norm += w * w;
+            }
+            if dot > 0.0 && other_norm > 0.0 {
+                scores.push((dot / (norm.sqrt() * other_norm.sqrt()), other));
+            }
+        }
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let mut related = vec![];
+        for (score, other) in scores.into_iter() {
+            if related.len() >= k {
+                break;
+            }
+            if let Some(entry) = self.get_entry(&other)? {
+                related.push(RelatedEntry { score, entry });
+            }
+        }
+        Ok(related)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn test_related() {
+        assert_eq!(
+            vec![("bread", 2), ("rye", 1)],
+            terms("The rye BREAD, and bread at 2024 on a")
+                .iter()
+                .map(|(t, c)| (t.as_str(), *c))
+                .collect::<Vec<_>>()
+        );
+
+        let db = test_db();
+        let entry = |id: &str, meta: &str, title: &str, body: &str| {
+            db.insert_entry(&Entry {
+                id: String::from(id),
+                created: 1,
+                meta: String::from(meta),
+                title: String::from(title),
+                body: String::from(body),
+                ..Entry::default()
+            })
+            .unwrap();
+        };
+        entry("1-a", "{Home}", "Sourdough", "Fed the starter, baked bread");
+        entry(
+            "1-b",
+            "{Work}",
+            "Borrow checker",
+            "Fought the rust compiler",
+        );
+        entry("1-c", "{Notes}", "Bread", "Sourdough starter smells sour");
+        entry("1-d", "{Work}", "Rust", "Compiler release notes");
+        entry("1-e", "{Home}", "Groceries", "Flour and bread");
+
+        let r = db.related_entries("1-a", 5).unwrap();
+        assert_eq!(
+            vec!["1-c", "1-e"],
+            r.iter().map(|r| r.entry.id.as_str()).collect::<Vec<_>>()
+        );
+        assert!(r[0].score > r[1].score && r[1].score > 0.0);
+        assert_eq!("{Notes}", r[0].entry.meta);
+        assert_eq!(1, db.related_entries("1-a", 1).unwrap().len());
+        assert!(db.related_entries("1-z", 5).is_err());
+
+        // The index follows updates and deletions.
+        entry("1-c", "{Notes}", "Rust", "Borrow checker and compiler");
+        assert_eq!("1-b", db.related_entries("1-c", 1).unwrap()[0].entry.id);
+        assert_eq!(
+            vec!["1-e"],
+            db.related_entries("1-a", 5)
+                .unwrap()
+                .iter()
+                .map(|r| r.entry.id.as_str())
+                .collect::<Vec<_>>()
+        );
+        db.delete_entry(&String::from("1-e")).unwrap();
+        assert!(db.related_entries("1-a", 5).unwrap().is_empty());
+        assert_eq!(4, db.count(b"n").unwrap());
+    }
+}
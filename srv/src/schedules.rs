@@ -0,0 +1,376 @@
+use crate::db::{extract_stream_names, DB};
+use crate::models::{Entry, EntryCreation, Field};
+use crate::time;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use chrono::{Datelike, Duration};
+use serde::{Deserialize, Serialize};
+
+// Schedules are stored by name in the `schedules` tree. Each run materializes the most recent
+// occurrence of a schedule since its `last_run`: runs missed while no runner was up are caught up
+// once, with an entry backdated to the missed occurrence. Entries carry a `schedule:<name>` field
+// and are looked up before being created so that a run interrupted before `last_run` is updated
+// doesn't create them twice.
+
+/// A rule creating an entry from the template `template` with the meta `meta` (eg: `{Review}`)
+/// at each occurrence of `cron`, see `Cron`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Schedule {
+    pub name: String,
+    pub cron: String,
+    pub template: String,
+    #[serde(default)]
+    pub meta: String,
+    /// UTC offset `cron` is evaluated at, the local one when the schedule is set if not set. The
+    /// offset is fixed: across daylight saving time changes occurrences drift by the shift (a
+    /// `0 9 * * *` schedule set in winter runs at 10:00 local time in summer) until the schedule
+    /// is set again.
+    #[serde(default)]
+    pub tz_offset: Option<i32>,
+    /// Time of the last occurrence materialized (or of the creation of the schedule).
+    #[serde(default)]
+    pub last_run: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ScheduleList {
+    pub total: usize,
+    pub schedules: Vec<Schedule>,
+}
+
+/// Reference to a schedule by name.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ScheduleRef {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ScheduleReport {
+    pub created: Vec<Entry>,
+    pub errors: Vec<String>,
+}
+
+/// A cron rule: `minute hour day-of-month month day-of-week`, each field being `*`, a value, a
+/// range (`1-5`), a list (`1,3`) or a step (`*/15`, `0-30/10`). Days of week go from 0 (Sunday)
+/// to 6, 7 being Sunday as well. As with cron, when both the day of month and the day of week
+/// are restricted, a day matching either one matches. `@hourly`, `@daily`, `@weekly`,
+/// `@monthly` and `@yearly` are also supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>> {
+    let mut set = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, s)) => (r, s.parse::<u32>()?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            r => match r.split_once('-') {
+                Some((a, b)) => (a.parse::<u32>()?, b.parse::<u32>()?),
+                None => {
+                    let v = r.parse::<u32>()?;
+                    (v, if step > 1 { max } else { v })
+                }
+            },
+        };
+        if from < min || to > max || from > to || step == 0 {
+            return Err(anyhow!("Invalid cron field: {}", field));
+        }
+        for v in (from..=to).step_by(step as usize) {
+            set[v as usize] = true;
+        }
+    }
+    Ok(set)
+}
+
+impl Cron {
+    pub fn parse(rule: &str) -> Result<Cron> {
+        let rule = match rule.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" => "0 0 1 1 *",
+            r => r,
+        };
+        let f = rule.split_whitespace().collect::<Vec<_>>();
+        if f.len() != 5 {
+            return Err(anyhow!("Invalid cron rule: {}", rule));
+        }
+        let mut weekdays = parse_field(f[4], 0, 7)?;
+        weekdays[0] = weekdays[0] || weekdays[7];
+        weekdays.truncate(7);
+        Ok(Cron {
+            minutes: parse_field(f[0], 0, 59)?,
+            hours: parse_field(f[1], 0, 23)?,
+            days: parse_field(f[2], 1, 31)?,
+            months: parse_field(f[3], 1, 12)?,
+            weekdays,
+            any_day: f[2] == "*",
+            any_weekday: f[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, d: chrono::NaiveDate) -> bool {
+        if !self.months[d.month() as usize] {
+            return false;
+        }
+        let day = self.days[d.day() as usize];
+        let weekday = self.weekdays[d.weekday().num_days_from_sunday() as usize];
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// Returns the most recent occurrence of the rule in `(after, until]`, at the UTC offset
+    /// `tz_offset`. Occurrences more than 4 years before `until` are not considered.
+    pub fn last_occurrence(&self, after: u64, until: u64, tz_offset: i32) -> Option<u64> {
+        let mut d = time::date(until, tz_offset);
+        let first = time::date(after, tz_offset).max(d - Duration::days(4 * 366));
+        while d >= first {
+            if self.matches_day(d) {
+                let start = time::day_start(d, tz_offset);
+                for h in (0..24).rev().filter(|h| self.hours[*h]) {
+                    for m in (0..60).rev().filter(|m| self.minutes[*m]) {
+                        let t = start + (h * 3600 + m * 60) as u64;
+                        if t <= until && t > after {
+                            return Some(t);
+                        }
+                    }
+                }
+            }
+            d -= Duration::days(1);
+        }
+        None
+    }
+}
+
+impl DB {
+    /// Creates or updates the schedule `schedule.name`. New schedules start from now, updated
+    /// ones keep their `last_run`.
+    pub fn set_schedule(&self, schedule: &Schedule) -> Result<Schedule> {
+        if schedule.name.trim().is_empty() {
+            return Err(anyhow!("Invalid schedule name: {}", schedule.name));
+        }
+        Cron::parse(&schedule.cron)?;
+        let now = time::now();
+        let last_run = match self.get_schedule(&schedule.name)? {
+            Some(s) => s.last_run,
+            None => now,
+        };
+        let schedule = Schedule {
+            tz_offset: Some(
+                schedule
+                    .tz_offset
+                    .unwrap_or_else(|| time::local_offset(now)),
+            ),
+            last_run,
+            ..schedule.clone()
+        };
+        self.schedules
+            .insert(schedule.name.as_bytes(), serialize(&schedule)?)?;
+        Ok(schedule)
+    }
+
+    pub fn get_schedule(&self, name: &str) -> Result<Option<Schedule>> {
+        match self.schedules.get(name.as_bytes())? {
+            Some(v) => Ok(Some(deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_schedules(&self) -> Result<Vec<Schedule>> {
+        self.schedules
+            .iter()
+            .values()
+            .map(|v| Ok(deserialize(&v?)?))
+            .collect()
+    }
+
+    pub fn delete_schedule(&self, name: &str) -> Result<()> {
+        self.schedules.remove(name.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the entry created for the occurrence `at` of the schedule `name`, if any.
+    fn scheduled_entry(&self, name: &str, at: u64) -> Result<Option<Entry>> {
+        let field = Field::Text(name.to_string());
+        for k in self.by_created.scan_prefix(at.to_be_bytes()).keys() {
+            if let Some(v) = self.entries.get(&k?[8..])? {
+                let e: Entry = deserialize(&v)?;
+                if e.fields.get("schedule") == Some(&field) {
+                    return Ok(Some(e));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn run_schedule(&self, schedule: &Schedule, now: u64) -> Result<Option<Entry>> {
+        let cron = Cron::parse(&schedule.cron)?;
+        let tz_offset = schedule
+            .tz_offset
+            .unwrap_or_else(|| time::local_offset(now));
+        let at = match cron.last_occurrence(schedule.last_run, now, tz_offset) {
+            Some(at) => at,
+            None => return Ok(None),
+        };
+
+        let entry = match self.scheduled_entry(&schedule.name, at)? {
+            Some(_) => None,
+            None => {
+                let template = match self.get_template(&schedule.template)? {
+                    Some(t) => t,
+                    None => return Err(anyhow!("Unknown template: {}", schedule.template)),
+                };
+                let stream = extract_stream_names(&schedule.meta)
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default();
+                let t = template.render(&stream, time::date(at, tz_offset));
+                Some(
+                    self.create_entry(&EntryCreation {
+                        meta: [
+                            schedule.meta.trim(),
+                            t.meta.trim(),
+                            &format!("schedule:{}", Field::Text(schedule.name.clone()).to_token()),
+                        ]
+                        .iter()
+                        .filter(|m| !m.is_empty())
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                        title: t.title,
+                        body: t.body,
+                        created: Some(at),
+                        tz_offset: Some(tz_offset),
+                    })?,
+                )
+            }
+        };
+
+        // The schedule may have been updated or deleted in the meantime.
+        if let Some(mut s) = self.get_schedule(&schedule.name)? {
+            s.last_run = s.last_run.max(at);
+            self.schedules.insert(s.name.as_bytes(), serialize(&s)?)?;
+        }
+        Ok(entry)
+    }
+
+    /// Materializes the occurrences of all schedules due at `now`, see `schedules`.
+    pub fn run_schedules(&self, now: u64) -> Result<ScheduleReport> {
+        let mut report = ScheduleReport::default();
+        for s in self.list_schedules()? {
+            match self.run_schedule(&s, now) {
+                Ok(Some(e)) => report.created.push(e),
+                Ok(None) => (),
+                Err(err) => report.errors.push(format!("{}: {}", s.name, err)),
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::templates::Template;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_schedules() {
+        let at = |d: u32, h: u32, m: u32| {
+            Utc.with_ymd_and_hms(2026, 10, d, h, m, 0)
+                .unwrap()
+                .timestamp() as u64
+        };
+        // Fridays at 17:00, 2026-10-16 and 2026-10-23 being Fridays.
+        let cron = Cron::parse("0 17 * * 5").unwrap();
+        assert_eq!(
+            Some(at(16, 17, 0)),
+            cron.last_occurrence(at(1, 0, 0), at(22, 0, 0), 0)
+        );
+        assert_eq!(None, cron.last_occurrence(at(16, 17, 0), at(22, 0, 0), 0));
+        assert_eq!(
+            Some(at(16, 15, 0)),
+            cron.last_occurrence(at(1, 0, 0), at(22, 0, 0), 2 * 3600)
+        );
+        let cron = Cron::parse("*/20 9-10 1,15 * 7").unwrap();
+        assert_eq!(
+            Some(at(18, 10, 40)),
+            cron.last_occurrence(at(1, 0, 0), at(19, 0, 0), 0)
+        );
+        assert_eq!(
+            Some(at(15, 10, 40)),
+            cron.last_occurrence(at(1, 0, 0), at(15, 11, 0), 0)
+        );
+        assert!(Cron::parse("0 24 * * *").is_err());
+        assert!(Cron::parse("@weekly").is_ok());
+        assert!(Cron::parse("* * *").is_err());
+
+        let db = test_db();
+        db.set_template(&Template {
+            name: String::from("Review"),
+            title: String::from("Review {{date}}"),
+            meta: String::from(""),
+            body: String::from("- [ ] Inbox zero"),
+        })
+        .unwrap();
+        let s = db
+            .set_schedule(&Schedule {
+                name: String::from("Weekly review"),
+                cron: String::from("0 17 * * 5"),
+                template: String::from("Review"),
+                meta: String::from("{Review}"),
+                tz_offset: Some(0),
+                last_run: 0,
+            })
+            .unwrap();
+        assert!(s.last_run > at(1, 0, 0));
+        // As if the schedule had been created at the start of the month.
+        let s = Schedule {
+            last_run: at(1, 0, 0),
+            ..s
+        };
+        db.schedules
+            .insert(s.name.as_bytes(), serialize(&s).unwrap())
+            .unwrap();
+
+        // Missed runs are caught up once, with the most recent occurrence.
+        let r = db.run_schedules(at(19, 9, 0)).unwrap();
+        assert_eq!(1, r.created.len());
+        let e = &r.created[0];
+        assert_eq!("Review 2026-10-16", e.title);
+        assert_eq!(at(16, 17, 0), e.created);
+        assert_eq!("{Review} schedule:\"Weekly review\"", e.meta);
+        assert!(db.run_schedules(at(19, 10, 0)).unwrap().created.is_empty());
+
+        // An interrupted run (entry created, `last_run` not updated) doesn't create it twice.
+        db.schedules
+            .insert(s.name.as_bytes(), serialize(&s).unwrap())
+            .unwrap();
+        assert!(db.run_schedules(at(19, 11, 0)).unwrap().created.is_empty());
+        assert_eq!(
+            at(16, 17, 0),
+            db.get_schedule(&s.name).unwrap().unwrap().last_run
+        );
+
+        db.delete_template("Review").unwrap();
+        let r = db.run_schedules(at(24, 0, 0)).unwrap();
+        assert_eq!(1, r.errors.len());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::db::DB;
+use crate::feed::Feed;
+use crate::models::Entry;
+use crate::sort::Sort;
+use crate::time;
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+use std::net::SocketAddr;
+use std::time::Duration;
+use warp::http::{header, Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+/// Maximum number of entries returned in a feed.
+pub const FEED_LIMIT: usize = 50;
+
+/// Interval at which schedules are run by `serve`, see `schedules`.
+pub const SCHEDULES_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Returns the filters of the HTTP server:
+/// - `GET /feeds/<stream path>.xml`: Atom feed of a stream and its children (eg:
+///   `/feeds/Work/ProjectX.xml`), supporting conditional GET through `ETag`.
+pub fn routes(db: DB) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let db = warp::any().map(move || db.clone());
+
+    warp::get()
+        .and(warp::path("feeds"))
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("host"))
+        .and(db)
+        .and_then(
+            |tail: warp::path::Tail,
+             if_none_match: Option<String>,
+             host: Option<String>,
+             db: DB| async move {
+                // Feeds are rendered on the blocking thread pool as they hit the database.
+                let path = String::from(tail.as_str());
+                let r = match tokio::task::spawn_blocking(move || {
+                    feed(&db, &path, if_none_match, host)
+                })
+                .await
+                {
+                    Ok(r) => r,
+                    Err(err) => Err(err.into()),
+                };
+                Ok::<_, Rejection>(match r {
+                    Ok(r) => r,
+                    Err(err) => {
+                        tracing::error!(error = format!("{}", err).as_str(), "feed");
+                        Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(String::from(""))
+                            .unwrap()
+                    }
+                })
+            },
+        )
+}
+
+/// Runs the schedules of `db` every `SCHEDULES_INTERVAL`, starting right away so that runs
+/// missed while the server was down are caught up. Runs happen on the blocking thread pool as
+/// they hit the database.
+pub async fn run_schedules(db: DB) {
+    let mut interval = tokio::time::interval(SCHEDULES_INTERVAL);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        let run = match tokio::task::spawn_blocking(move || db.run_schedules(time::now())).await {
+            Ok(r) => r,
+            Err(err) => Err(err.into()),
+        };
+        match run {
+            Ok(r) => {
+                for e in r.created.iter() {
+                    tracing::info!(id = e.id.as_str(), "run_schedules");
+                }
+                for err in r.errors.iter() {
+                    tracing::error!(error = err.as_str(), "run_schedules");
+                }
+            }
+            Err(err) => tracing::error!(error = format!("{}", err).as_str(), "run_schedules"),
+        }
+    }
+}
+
+pub async fn serve(db: DB, addr: SocketAddr) {
+    tracing::info!(addr = addr.to_string().as_str(), "serve");
+    tokio::spawn(run_schedules(db.clone()));
+    warp::serve(routes(db)).run(addr).await
+}
+
+fn not_found() -> Response<String> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(String::from(""))
+        .unwrap()
+}
+
+/// Computes the `ETag` of a feed from the ids and update times of its entries, so that it changes
+/// whenever an entry is added (even backdated), edited or removed. The hash is 64-bit FNV-1a,
+/// which unlike `DefaultHasher` is stable across restarts and Rust versions.
+fn etag(entries: &[Entry]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for e in entries {
+        let updated = e.updated.to_le_bytes();
+        for b in e.id.bytes().chain(Some(0)).chain(updated.iter().copied()) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("\"{}-{:016x}\"", entries.len(), hash)
+}
+
+fn feed(
+    db: &DB,
+    path: &str,
+    if_none_match: Option<String>,
+    host: Option<String>,
+) -> Result<Response<String>> {
+    let name = match path.strip_suffix(".xml") {
+        Some(n) => percent_decode_str(n).decode_utf8()?.to_string(),
+        None => return Ok(not_found()),
+    };
+    let stream = match db.stream_by_name(&name, false)? {
+        Some(s) => s,
+        None => return Ok(not_found()),
+    };
+
+    let entries = db.first_entries(&format!("{{{}}}", stream.name), Sort::default(), FEED_LIMIT)?;
+
+    let etag = etag(&entries);
+    if let Some(inm) = if_none_match {
+        if inm.split(',').any(|t| t.trim() == etag || t.trim() == "*") {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .body(String::from(""))
+                .unwrap());
+        }
+    }
+
+    let link = match host {
+        Some(h) => format!("http://{}/feeds/{}", h, path),
+        None => format!("/feeds/{}", path),
+    };
+    let body = Feed {
+        id: format!("urn:dump:stream:{}", stream.id),
+        title: stream.name.clone(),
+        author: String::from("Dump"),
+        link,
+        entry_link: None,
+    }
+    .render(&entries);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")
+        .header(header::ETAG, etag)
+        .body(body)
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+    use crate::models::EntryCreation;
+
+    #[tokio::test]
+    async fn test_feed() {
+        let db = test_db();
+        let e = test_entry(&db, "{Standup/Daily Sync}", "Monday", "Shipped *it*");
+        test_entry(&db, "{Other}", "Unrelated", "");
+        db.stream_by_name(&String::from("Standup"), true).unwrap();
+        let routes = routes(db.clone());
+
+        let r = warp::test::request()
+            .path("/feeds/Standup.xml")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, r.status());
+        let etag = r.headers()[header::ETAG].to_str().unwrap().to_string();
+        let body = std::str::from_utf8(r.body()).unwrap();
+        assert!(body.contains("<title>Monday</title>"));
+        assert!(body.contains("<author><name>Dump</name></author>"));
+        assert!(!body.contains("Unrelated"));
+
+        let r = warp::test::request()
+            .path("/feeds/Standup/Daily%20Sync.xml")
+            .header("if-none-match", etag.as_str())
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::NOT_MODIFIED, r.status());
+
+        // Backdated entries and edits change the `ETag`.
+        let feed_etag = |path: &'static str| {
+            let routes = routes.clone();
+            async move {
+                let r = warp::test::request().path(path).reply(&routes).await;
+                r.headers()[header::ETAG].to_str().unwrap().to_string()
+            }
+        };
+        db.create_entry(&EntryCreation {
+            meta: String::from("{Standup}"),
+            title: String::from("Long ago"),
+            body: String::from(""),
+            created: Some(1),
+            tz_offset: None,
+        })
+        .unwrap();
+        let backdated = feed_etag("/feeds/Standup.xml").await;
+        assert_ne!(etag, backdated);
+        let mut edited = db.get_entry(&e.id).unwrap().unwrap();
+        edited.body = String::from("Shipped it, really");
+        edited.updated += 10;
+        db.restore_entry(&edited).unwrap();
+        assert_ne!(backdated, feed_etag("/feeds/Standup.xml").await);
+        // The hash doesn't depend on the process, so `ETag`s survive restarts.
+        assert_eq!("\"0-cbf29ce484222325\"", super::etag(&[]));
+
+        let r = warp::test::request()
+            .path("/feeds/Unknown.xml")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::NOT_FOUND, r.status());
+    }
+}
@@ -0,0 +1,315 @@
+use crate::db::{extract_stream_names, validate_entry_id, DB};
+use crate::feed::{escape, render_markdown, rfc3339, Feed};
+use crate::models::{parent_names, Entry};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+pub struct SiteOptions {
+    pub path: String,
+    pub query: String,
+    #[serde(default)]
+    pub title: String,
+    /// Absolute URL the site is published at, used for the Atom feeds. Links are relative if it
+    /// is empty.
+    #[serde(default)]
+    pub base_url: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SiteReport {
+    pub path: String,
+    pub entries: usize,
+    pub streams: usize,
+}
+
+/// Turns a component of a stream name into a directory name.
+fn path_component(c: &str) -> String {
+    let s = c
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let s = s.trim_matches('-');
+    if s.is_empty() {
+        String::from("_")
+    } else {
+        String::from(s)
+    }
+}
+
+/// Turns a stream name into a path, one directory per component of its hierarchy.
+pub fn stream_path(name: &str) -> String {
+    name.split('/')
+        .map(path_component)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Maps the stream names of `names`, which must include their parents, to distinct paths: a
+/// component that would land on the directory of another stream (`C++` and `C--` both giving
+/// `c`) gets a `-2`, `-3`, ... suffix, the first stream by name keeping the plain one.
+pub fn stream_paths<'a>(names: impl Iterator<Item = &'a String>) -> BTreeMap<String, String> {
+    let mut names = names.collect::<Vec<_>>();
+    // Parents sort before their children.
+    names.sort();
+    let mut paths: BTreeMap<String, String> = BTreeMap::new();
+    let mut used: HashSet<String> = HashSet::new();
+    for name in names {
+        let (parent, c) = match name.rsplit_once('/') {
+            Some((p, c)) => (paths.get(p).map(|p| format!("{}/", p)), c),
+            None => (None, name.as_str()),
+        };
+        let base = format!("{}{}", parent.unwrap_or_default(), path_component(c));
+        let mut path = base.clone();
+        let mut i = 1;
+        while used.contains(&path) {
+            i += 1;
+            path = format!("{}-{}", base, i);
+        }
+        used.insert(path.clone());
+        paths.insert(name.clone(), path);
+    }
+    paths
+}
+
+fn entry_path(entry: &Entry) -> String {
+    format!("entries/{}.html", entry.id)
+}
+
+fn page(site: &str, title: &str, root: &str, feed: &str, content: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <title>{title}</title>\n\
+         <link rel=\"alternate\" type=\"application/atom+xml\" href=\"{feed}\">\n\
+         </head>\n\
+         <body>\n\
+         <header><a href=\"{root}index.html\">{site}</a></header>\n\
+         <main>\n{content}</main>\n\
+         </body>\n\
+         </html>\n",
+        title = escape(title),
+        feed = escape(feed),
+        root = root,
+        site = escape(site),
+        content = content,
+    )
+}
+
+fn entry_list(root: &str, entries: &[&Entry]) -> String {
+    let mut out = String::from("<ul class=\"entries\">\n");
+    for e in entries {
+        out.push_str(&format!(
+            "<li><a href=\"{}{}\">{}</a> <time datetime=\"{}\">{}</time></li>\n",
+            root,
+            entry_path(e),
+            escape(&e.title),
+            rfc3339(e.created),
+            e.date().format("%Y-%m-%d"),
+        ));
+    }
+    out.push_str("</ul>\n");
+    out
+}
+
+fn stream_list(root: &str, paths: &BTreeMap<String, String>, names: &[&String]) -> String {
+    let mut out = String::from("<ul class=\"streams\">\n");
+    for n in names {
+        out.push_str(&format!(
+            "<li><a href=\"{}streams/{}/index.html\">{}</a></li>\n",
+            root,
+            paths.get(*n).cloned().unwrap_or_else(|| stream_path(n)),
+            escape(n),
+        ));
+    }
+    out.push_str("</ul>\n");
+    out
+}
+
+impl DB {
+    /// Renders the entries matching `options.query` as a static HTML site: an index per stream
+    /// (including the entries of its children), a page per entry and Atom feeds.
+    pub fn generate_site(&self, options: &SiteOptions) -> Result<SiteReport> {
+        let dir = Path::new(&options.path);
+        let site = if options.title.is_empty() {
+            "Dump"
+        } else {
+            options.title.as_str()
+        };
+        let (_, entries) = self.list_entries(&options.query, 0, usize::MAX)?;
+        // Entry ids become file names (see `entry_path`), which must stay within `dir`.
+        for e in entries.iter() {
+            validate_entry_id(&e.id)?;
+        }
+
+        // Map each stream, and all its parents, to the entries it contains.
+        let mut streams: BTreeMap<String, Vec<&Entry>> = BTreeMap::new();
+        for e in entries.iter() {
+            for sn in extract_stream_names(&e.meta) {
+                for p in parent_names(&sn) {
+                    let l = streams.entry(p).or_default();
+                    if !l.iter().any(|x| x.id == e.id) {
+                        l.push(e);
+                    }
+                }
+            }
+        }
+        let paths = stream_paths(streams.keys());
+        let children = |name: Option<&String>| -> Vec<&String> {
+            streams
+                .keys()
+                .filter(|n| {
+                    let p = parent_names(n);
+                    match name {
+                        Some(name) => p.len() >= 2 && p[p.len() - 2] == *name,
+                        None => p.len() == 1,
+                    }
+                })
+                .collect()
+        };
+
+        let entry_link = |e: &Entry| format!("{}{}", options.base_url, entry_path(e));
+        let feed = |title: &str, path: &str, entries: &[Entry]| -> Result<()> {
+            let feed = Feed {
+                id: format!("urn:dump:feed:{}", path),
+                title: String::from(title),
+                author: String::from(site),
+                link: format!("{}{}", options.base_url, path),
+                entry_link: Some(&entry_link),
+            };
+            fs::write(dir.join(path), feed.render(entries))?;
+            Ok(())
+        };
+
+        fs::create_dir_all(dir.join("entries"))?;
+
+        let all = entries.iter().collect::<Vec<_>>();
+        let content = format!(
+            "<h1>{}</h1>\n{}{}",
+            escape(site),
+            stream_list("", &paths, &children(None)),
+            entry_list("", &all)
+        );
+        fs::write(
+            dir.join("index.html"),
+            page(site, site, "", "feed.xml", &content),
+        )?;
+        feed(site, "feed.xml", &entries)?;
+
+        for e in entries.iter() {
+            let root = "../";
+            let content = format!(
+                "<article>\n<h1>{}</h1>\n<time datetime=\"{}\">{}</time>\n{}{}</article>\n",
+                escape(&e.title),
+                rfc3339(e.created),
+                e.date().format("%Y-%m-%d"),
+                stream_list(
+                    root,
+                    &paths,
+                    &extract_stream_names(&e.meta).iter().collect::<Vec<_>>()
+                ),
+                render_markdown(&e.body),
+            );
+            fs::write(
+                dir.join(entry_path(e)),
+                page(site, &e.title, root, "../feed.xml", &content),
+            )?;
+        }
+
+        for (name, l) in streams.iter() {
+            let path = format!("streams/{}", paths[name]);
+            let root = "../".repeat(path.split('/').count());
+            let content = format!(
+                "<h1>{}</h1>\n{}{}",
+                escape(name),
+                stream_list(&root, &paths, &children(Some(name))),
+                entry_list(&root, l)
+            );
+            fs::create_dir_all(dir.join(&path))?;
+            fs::write(
+                dir.join(&path).join("index.html"),
+                page(site, name, &root, "feed.xml", &content),
+            )?;
+            let l = l.iter().map(|e| (*e).clone()).collect::<Vec<_>>();
+            feed(name, &format!("{}/feed.xml", path), &l)?;
+        }
+
+        Ok(SiteReport {
+            path: options.path.clone(),
+            entries: entries.len(),
+            streams: streams.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_generate_site() {
+        assert_eq!("reading/notes", stream_path("Reading/Notes"));
+        assert_eq!("my-blog/_", stream_path("My Blog/!"));
+        let names = ["C++", "C--", "C--/A.B", "C--/A B", "c"].map(String::from);
+        let paths = stream_paths(names.iter());
+        assert_eq!(
+            vec!["c", "c-2", "c-2/a-b", "c-2/a-b-2", "c-3"],
+            ["C++", "C--", "C--/A B", "C--/A.B", "c"]
+                .iter()
+                .map(|n| paths[*n].as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let db = test_db();
+        let dir = std::env::temp_dir().join(format!("dump-test-{}", nanoid::nanoid!()));
+        let e = test_entry(&db, "{Reading/Notes}", "Dune <3", "# Notes\n\n*Great*");
+        test_entry(&db, "{Private}", "Secret", "");
+
+        let report = db
+            .generate_site(&SiteOptions {
+                path: dir.display().to_string(),
+                query: String::from("{Reading/Notes}"),
+                title: String::from("Blog"),
+                base_url: String::from("https://example.com/"),
+            })
+            .unwrap();
+        assert_eq!((1, 2), (report.entries, report.streams));
+
+        let page = fs::read_to_string(dir.join(format!("entries/{}.html", e.id))).unwrap();
+        assert!(page.contains("<title>Dune &lt;3</title>"));
+        assert!(page.contains("<em>Great</em>"));
+        assert!(page.contains("href=\"../streams/reading/notes/index.html\""));
+        let index = fs::read_to_string(dir.join("streams/reading/index.html")).unwrap();
+        assert!(index.contains("href=\"../../streams/reading/notes/index.html\""));
+        assert!(index.contains(&format!("href=\"../../entries/{}.html\"", e.id)));
+        let feed = fs::read_to_string(dir.join("streams/reading/notes/feed.xml")).unwrap();
+        assert!(feed.contains(&format!("https://example.com/entries/{}.html", e.id)));
+        assert!(!dir.join("streams/private").exists());
+
+        // Entries whose id isn't path-safe are refused.
+        db.insert_entry(&Entry {
+            id: String::from("../../escape"),
+            meta: String::from("{Reading}"),
+            ..Entry::default()
+        })
+        .unwrap();
+        assert!(db
+            .generate_site(&SiteOptions {
+                path: dir.display().to_string(),
+                query: String::from("{Reading}"),
+                title: String::from(""),
+                base_url: String::from(""),
+            })
+            .is_err());
+        assert!(!dir.join("../escape.html").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use crate::db::DB;
+use crate::models::Entry;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Entries are sorted by walking an index instead of sorting the matches in memory:
+// - by creation time: the `by_created` tree, keyed by `<created as big-endian u64><id>`. Ids
+//   embed the creation time of entries but stay stable when it is changed (backdated entries), so
+//   the `entries` tree order can't be relied on.
+// - by update time: the `by_updated` tree, keyed by `<updated as big-endian u64><id>`.
+// - by title: the `titles` tree, keyed by `<lowercased title>\0<id>`.
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    CreatedDesc,
+    CreatedAsc,
+    UpdatedDesc,
+    UpdatedAsc,
+    Title,
+}
+
+fn time_key(secs: u64, id: &str) -> Vec<u8> {
+    let mut k = secs.to_be_bytes().to_vec();
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+pub fn created_key(e: &Entry) -> Vec<u8> {
+    time_key(e.created, &e.id)
+}
+
+pub fn updated_key(e: &Entry) -> Vec<u8> {
+    time_key(e.updated, &e.id)
+}
+
+pub fn title_key(e: &Entry) -> Vec<u8> {
+    format!("{}\0{}", e.title.to_lowercase(), e.id).into_bytes()
+}
+
+impl DB {
+    /// Updates the `by_created` and `by_updated` indexes for `entry`, `previous` being the entry
+    /// as stored before the update.
+    pub(crate) fn index_times(&self, entry: &Entry, previous: Option<&Entry>) -> Result<()> {
+        if let Some(p) = previous {
+            self.by_created.remove(created_key(p))?;
+            self.by_updated.remove(updated_key(p))?;
+        }
+        self.by_created.insert(created_key(entry), &[])?;
+        self.by_updated.insert(updated_key(entry), &[])?;
+        Ok(())
+    }
+
+    pub(crate) fn unindex_times(&self, entry: &Entry) -> Result<()> {
+        self.by_created.remove(created_key(entry))?;
+        self.by_updated.remove(updated_key(entry))?;
+        Ok(())
+    }
+
+    /// Returns the raw records of all entries in the order of `sort`.
+    pub(crate) fn sorted_entries(
+        &self,
+        sort: Sort,
+    ) -> Box<dyn Iterator<Item = Result<sled::IVec>> + '_> {
+        let from_index = move |id: Vec<u8>| -> Option<Result<sled::IVec>> {
+            self.entries.get(id).map_err(|e| e.into()).transpose()
+        };
+        let from_time_index = move |k: sled::Result<sled::IVec>| match k {
+            Ok(k) => from_index(k[8..].to_vec()),
+            Err(e) => Some(Err(e.into())),
+        };
+        match sort {
+            Sort::CreatedDesc => Box::new(
+                self.by_created
+                    .iter()
+                    .keys()
+                    .rev()
+                    .filter_map(from_time_index),
+            ),
+            Sort::CreatedAsc => Box::new(self.by_created.iter().keys().filter_map(from_time_index)),
+            Sort::UpdatedDesc => Box::new(
+                self.by_updated
+                    .iter()
+                    .keys()
+                    .rev()
+                    .filter_map(from_time_index),
+            ),
+            Sort::UpdatedAsc => Box::new(self.by_updated.iter().keys().filter_map(from_time_index)),
+            Sort::Title => Box::new(self.titles.iter().keys().filter_map(move |k| match k {
+                Ok(k) => {
+                    let id = match k.iter().rposition(|b| *b == 0) {
+                        Some(p) => k[p + 1..].to_vec(),
+                        None => k.to_vec(),
+                    };
+                    from_index(id)
+                }
+                Err(e) => Some(Err(e.into())),
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+    use crate::models::EntryCreation;
+
+    #[test]
+    fn test_sort() {
+        let db = test_db();
+        for (id, title) in [
+            ("1626000000-a", "Beta"),
+            ("1626000001-b", "alpha"),
+            ("1626000002-c", ""),
+        ] {
+            db.insert_entry(&Entry {
+                id: String::from(id),
+                created: 1626000000,
+                title: String::from(title),
+                ..Entry::default()
+            })
+            .unwrap();
+        }
+        let ids = |sort: Sort| {
+            db.list_entries_sorted("", sort, 0, 10)
+                .unwrap()
+                .1
+                .iter()
+                .map(|e| e.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["1626000002-c", "1626000001-b", "1626000000-a"],
+            ids(Sort::CreatedDesc)
+        );
+        assert_eq!(
+            vec!["1626000000-a", "1626000001-b", "1626000002-c"],
+            ids(Sort::CreatedAsc)
+        );
+        assert_eq!(
+            vec!["1626000002-c", "1626000001-b", "1626000000-a"],
+            ids(Sort::Title)
+        );
+        let (total, page) = db.list_entries_sorted("", Sort::CreatedAsc, 1, 1).unwrap();
+        assert_eq!((3, "1626000001-b"), (total, page[0].id.as_str()));
+
+        // New entries are updated when created, imported ones keep their creation time.
+        let e = db
+            .get_entry(&String::from("1626000000-a"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(1626000000, e.updated);
+        let created = test_entry(&db, "", "Gamma", "");
+        assert!(created.updated >= created.created);
+
+        // Updates only change `updated` when the content changes.
+        db.insert_entry(&e).unwrap();
+        assert_eq!(1626000000, db.get_entry(&e.id).unwrap().unwrap().updated);
+        db.insert_entry(&Entry {
+            body: String::from("Edited"),
+            ..e.clone()
+        })
+        .unwrap();
+        let updated = db.get_entry(&e.id).unwrap().unwrap().updated;
+        assert!(updated > 1626000000);
+
+        let u = ids(Sort::UpdatedDesc);
+        assert_eq!(4, u.len());
+        assert_eq!("1626000001-b", u[3]);
+        assert_eq!(
+            vec!["1626000001-b", "1626000002-c"],
+            ids(Sort::UpdatedAsc)[..2].to_vec()
+        );
+        assert_eq!(
+            1,
+            db.list_entries_sorted("Edited", Sort::UpdatedDesc, 0, 10)
+                .unwrap()
+                .0
+        );
+
+        // Backdated entries are ordered by their creation time, which can be changed later on
+        // without changing their id.
+        let d = db
+            .create_entry(&EntryCreation {
+                meta: String::from(""),
+                title: String::from("Delta"),
+                body: String::from(""),
+                created: Some(1625000000),
+                tz_offset: None,
+            })
+            .unwrap();
+        assert!(d.id.starts_with("1625000000-"));
+        assert_eq!(Some(&d.id), ids(Sort::CreatedAsc).first());
+        db.insert_entry(&Entry {
+            created: 1626000001,
+            ..d.clone()
+        })
+        .unwrap();
+        let c = ids(Sort::CreatedAsc);
+        assert_eq!(vec!["1626000000-a", "1626000001-b"], c[..2].to_vec());
+        assert_eq!(d.id, c[3]);
+        assert!(db.get_entry(&d.id).unwrap().unwrap().updated > 1626000001);
+    }
+}
@@ -0,0 +1,511 @@
+use crate::db::{extract_stream_ids, key, update_record, DB};
+use crate::models::Entry;
+use crate::sort::Sort;
+use crate::time::{self, Bucket};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// The `stats` tree aggregates entries, with `\0` separated keys:
+// - `s\0<stream id>\0<YYYY-MM-DD>`: `Counts` of the entries of a day directly in a stream, `*`
+//   standing for all entries.
+// - `e\0<entry id>`: the `Contribution` of an entry to the aggregates, subtracted when the entry
+//   is updated or deleted.
+// - `m\0<stream id>\0<entry id>`: marks the entries directly in several streams, under each of
+//   their streams.
+// Stats of a stream roll up the aggregates of its descendants, the entries in several of them
+// (see `m`) being deducted so that each entry counts once, as in `list_entries`.
+
+const ALL: &str = "*";
+
+/// Number of streams returned in `Stats.streams`.
+pub const TOP_STREAMS: usize = 5;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub entries: u64,
+    pub words: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+struct Contribution {
+    date: String,
+    words: u64,
+    streams: Vec<String>,
+}
+
+/// Request for the stats of the entries matching `query`. Queries made only of (at most one)
+/// stream are answered from the `stats` aggregates, others from the matching entries.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct StatsRequest {
+    #[serde(default)]
+    pub query: String,
+}
+
+/// Request for the heatmap of the entries matching `query` (as listed by `list_entries`, entries of
+/// sub-streams counting for their parents) from `from` to `to` included. Dates are `YYYY-MM-DD`,
+/// `today` or `yesterday`; `to` defaults to today and the range to the `HEATMAP_DAYS` days ending
+/// on `to`. Ranges are limited to `MAX_HEATMAP_DAYS` days.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct HeatmapRequest {
+    #[serde(default)]
+    pub query: String,
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Option<String>,
+}
+
+/// Number of days of a heatmap when `HeatmapRequest.from` is not set.
+pub const HEATMAP_DAYS: i64 = 365;
+
+/// Maximum number of days of a heatmap (about 20 years).
+pub const MAX_HEATMAP_DAYS: i64 = 7320;
+
+/// Daily counts from `from` to `to`: `entries[i]` and `words[i]` are the counts of the `i`-th day
+/// of the range.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Heatmap {
+    pub from: String,
+    pub to: String,
+    pub entries: Vec<u64>,
+    pub words: Vec<u64>,
+}
+
+/// Counts of a day, week or month (`label`, see `Bucket::label`).
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Bin {
+    pub label: String,
+    pub entries: u64,
+    pub words: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct StreamStats {
+    pub name: String,
+    pub entries: u64,
+    pub words: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub entries: u64,
+    pub words: u64,
+    pub days: Vec<Bin>,
+    pub weeks: Vec<Bin>,
+    pub months: Vec<Bin>,
+    /// Number of consecutive days with entries up to today (or yesterday if there is no entry
+    /// today yet).
+    pub current_streak: u64,
+    pub longest_streak: u64,
+    /// Streams with the most entries (descendants of the stream for stream stats).
+    pub streams: Vec<StreamStats>,
+}
+
+pub fn count_words(entry: &Entry) -> u64 {
+    (entry.title.split_whitespace().count() + entry.body.split_whitespace().count()) as u64
+}
+
+fn bins(days: &BTreeMap<NaiveDate, Counts>, bucket: Bucket) -> Vec<Bin> {
+    let mut bins: Vec<Bin> = vec![];
+    for (d, c) in days.iter() {
+        let label = bucket.label(*d);
+        match bins.last_mut() {
+            Some(b) if b.label == label => {
+                b.entries += c.entries;
+                b.words += c.words;
+            }
+            _ => bins.push(Bin {
+                label,
+                entries: c.entries,
+                words: c.words,
+            }),
+        }
+    }
+    bins
+}
+
+/// Returns the current and longest streaks of consecutive `days`, see `Stats`.
+fn streaks(days: &BTreeMap<NaiveDate, Counts>, today: NaiveDate) -> (u64, u64) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<NaiveDate> = None;
+    for d in days.keys() {
+        run = match previous {
+            Some(p) if *d - p == Duration::days(1) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(*d);
+    }
+    let current = match previous {
+        Some(p) if p == today || p == today - Duration::days(1) => run,
+        _ => 0,
+    };
+    (current, longest)
+}
+
+impl Stats {
+    fn build(
+        days: BTreeMap<NaiveDate, Counts>,
+        streams: Vec<StreamStats>,
+        today: NaiveDate,
+    ) -> Stats {
+        let (current_streak, longest_streak) = streaks(&days, today);
+        let mut streams = streams
+            .into_iter()
+            .filter(|s| s.entries > 0)
+            .collect::<Vec<_>>();
+        streams.sort_by(|a, b| b.entries.cmp(&a.entries).then(a.name.cmp(&b.name)));
+        streams.truncate(TOP_STREAMS);
+        Stats {
+            entries: days.values().map(|c| c.entries).sum(),
+            words: days.values().map(|c| c.words).sum(),
+            days: bins(&days, Bucket::Day),
+            weeks: bins(&days, Bucket::Week),
+            months: bins(&days, Bucket::Month),
+            current_streak,
+            longest_streak,
+            streams,
+        }
+    }
+}
+
+impl DB {
+    /// Adds (or subtracts if `sign` is negative) `delta` to the counts of `stream` on `date`,
+    /// atomically.
+    fn add_counts(&self, stream: &str, date: &str, delta: Counts, sign: i64) -> Result<()> {
+        update_record(
+            &self.stats,
+            &key(&["s", stream, date]),
+            |c: Option<Counts>| {
+                let mut c = c.unwrap_or_default();
+                if sign > 0 {
+                    c.entries += delta.entries;
+                    c.words += delta.words;
+                } else {
+                    c.entries = c.entries.saturating_sub(delta.entries);
+                    c.words = c.words.saturating_sub(delta.words);
+                }
+                if c.entries == 0 {
+                    None
+                } else {
+                    Some(c)
+                }
+            },
+        )
+    }
+
+    fn apply(&self, c: &Contribution, sign: i64) -> Result<()> {
+        let delta = Counts {
+            entries: 1,
+            words: c.words,
+        };
+        self.add_counts(ALL, &c.date, delta, sign)?;
+        for s in c.streams.iter() {
+            self.add_counts(s, &c.date, delta, sign)?;
+        }
+        Ok(())
+    }
+
+    /// Updates the `stats` aggregates for `entry`, whose `meta` is id-based.
+    pub(crate) fn index_stats(&self, entry: &Entry) -> Result<()> {
+        self.unindex_stats(&entry.id)?;
+        let mut streams = extract_stream_ids(&entry.meta);
+        streams.sort();
+        streams.dedup();
+        let c = Contribution {
+            date: Bucket::Day.label(entry.date()),
+            words: count_words(entry),
+            streams,
+        };
+        self.apply(&c, 1)?;
+        self.stats.insert(key(&["e", &entry.id]), serialize(&c)?)?;
+        if c.streams.len() > 1 {
+            for s in c.streams.iter() {
+                self.stats.insert(key(&["m", s, &entry.id]), &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unindex_stats(&self, id: &str) -> Result<()> {
+        if let Some(v) = self.stats.remove(key(&["e", id]))? {
+            let c: Contribution = deserialize(&v)?;
+            self.apply(&c, -1)?;
+            for s in c.streams.iter() {
+                self.stats.remove(key(&["m", s, id]))?;
+            }
+        }
+        // Markers used to be keyed by entry only (before postprocess version 7).
+        self.stats.remove(key(&["m", id]))?;
+        Ok(())
+    }
+
+    /// Sums the daily aggregates of the streams `ids`, counting once the entries in several of
+    /// them.
+    fn rolled_up_days(&self, ids: &[String]) -> Result<BTreeMap<NaiveDate, Counts>> {
+        let mut days = self.aggregated_days(ids)?;
+        let mut entries: BTreeSet<Vec<u8>> = BTreeSet::new();
+        for id in ids {
+            let mut prefix = key(&["m", id]);
+            prefix.push(0);
+            for k in self.stats.scan_prefix(&prefix).keys() {
+                entries.insert(k?[prefix.len()..].to_vec());
+            }
+        }
+        for id in entries {
+            let c: Contribution = match self.stats.get([b"e\0", &id[..]].concat())? {
+                Some(v) => deserialize(&v)?,
+                None => continue,
+            };
+            let in_scope = c.streams.iter().filter(|s| ids.contains(s)).count() as u64;
+            if in_scope <= 1 {
+                continue;
+            }
+            if let Ok(d) = NaiveDate::parse_from_str(&c.date, "%Y-%m-%d") {
+                if let Some(e) = days.get_mut(&d) {
+                    e.entries = e.entries.saturating_sub(in_scope - 1);
+                    e.words = e.words.saturating_sub((in_scope - 1) * c.words);
+                }
+            }
+        }
+        Ok(days)
+    }
+
+    /// Sums the daily aggregates of the streams `ids`.
+    fn aggregated_days(&self, ids: &[String]) -> Result<BTreeMap<NaiveDate, Counts>> {
+        let mut days: BTreeMap<NaiveDate, Counts> = BTreeMap::new();
+        for id in ids {
+            let mut prefix = key(&["s", id]);
+            prefix.push(0);
+            for x in self.stats.scan_prefix(&prefix) {
+                let (k, v) = x?;
+                let c: Counts = deserialize(&v)?;
+                let date = String::from_utf8_lossy(&k[prefix.len()..]).to_string();
+                if let Ok(d) = NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+                    let e = days.entry(d).or_default();
+                    e.entries += c.entries;
+                    e.words += c.words;
+                }
+            }
+        }
+        Ok(days)
+    }
+
+    pub fn stats(&self, query: &str) -> Result<Stats> {
+        let today = time::today();
+        let q = self.parse_query(query);
+        let streams = self.streams_from_query(query)?;
+        if !q.streams.is_empty() && streams.len() < q.streams.len() {
+            return Ok(Stats::default());
+        }
+
+        if q.filters.is_empty() && q.is.is_empty() && q.text.is_empty() && streams.len() <= 1 {
+            let scope = {
+                let catalog = self.catalog.read().unwrap();
+                match streams.first() {
+                    Some(s) => std::iter::once(s)
+                        .chain(catalog.descendants(&s.name))
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                    None => catalog.all().into_iter().cloned().collect::<Vec<_>>(),
+                }
+            };
+            let days = match streams.first() {
+                Some(_) => {
+                    self.rolled_up_days(&scope.iter().map(|s| s.id.clone()).collect::<Vec<_>>())?
+                }
+                None => self.aggregated_days(&[String::from(ALL)])?,
+            };
+            let mut top = vec![];
+            for s in scope.iter() {
+                if streams.first().map(|f| f.id == s.id).unwrap_or(false) {
+                    continue;
+                }
+                let d = self.aggregated_days(std::slice::from_ref(&s.id))?;
+                top.push(StreamStats {
+                    name: s.name.clone(),
+                    entries: d.values().map(|c| c.entries).sum(),
+                    words: d.values().map(|c| c.words).sum(),
+                });
+            }
+            return Ok(Stats::build(days, top, today));
+        }
+
+        // Other queries are answered from the matching entries.
+        let (_, entries) = self.list_entries_sorted(query, Sort::CreatedAsc, 0, usize::MAX)?;
+        let mut days: BTreeMap<NaiveDate, Counts> = BTreeMap::new();
+        let mut top: HashMap<String, Counts> = HashMap::new();
+        for e in entries.iter() {
+            let words = count_words(e);
+            let c = days.entry(e.date()).or_default();
+            c.entries += 1;
+            c.words += words;
+            for s in crate::db::extract_stream_names(&e.meta) {
+                let c = top.entry(s).or_default();
+                c.entries += 1;
+                c.words += words;
+            }
+        }
+        let top = top
+            .into_iter()
+            .map(|(name, c)| StreamStats {
+                name,
+                entries: c.entries,
+                words: c.words,
+            })
+            .collect();
+        Ok(Stats::build(days, top, today))
+    }
+
+    /// Returns the heatmap of the entries matching `query` from `from` to `to` included, see
+    /// `HeatmapRequest`.
+    pub fn heatmap(&self, query: &str, from: NaiveDate, to: NaiveDate) -> Result<Heatmap> {
+        if from > to {
+            return Err(anyhow!("Invalid range: {} > {}", from, to));
+        }
+        let len = (to - from).num_days() + 1;
+        if len > MAX_HEATMAP_DAYS {
+            return Err(anyhow!(
+                "Invalid range: {} days (at most {})",
+                len,
+                MAX_HEATMAP_DAYS
+            ));
+        }
+        let len = len as usize;
+        let mut heatmap = Heatmap {
+            from: Bucket::Day.label(from),
+            to: Bucket::Day.label(to),
+            entries: vec![0; len],
+            words: vec![0; len],
+        };
+        let q = self.parse_query(query);
+        if self.streams_from_query(query)?.len() < q.streams.len() {
+            return Ok(heatmap);
+        }
+
+        for e in self.entries_between(query, from, to)? {
+            let i = (e.date() - from).num_days() as usize;
+            heatmap.entries[i] += 1;
+            heatmap.words[i] += count_words(&e);
+        }
+        Ok(heatmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn test_stats() {
+        let ymd = |m, d| NaiveDate::from_ymd_opt(2026, m, d).unwrap();
+        let c = Counts {
+            entries: 1,
+            words: 1,
+        };
+        let days = [ymd(9, 28), ymd(9, 29), ymd(9, 30), ymd(10, 18), ymd(10, 19)]
+            .iter()
+            .map(|d| (*d, c))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!((2, 3), streaks(&days, ymd(10, 19)));
+        assert_eq!((2, 3), streaks(&days, ymd(10, 20)));
+        assert_eq!((0, 3), streaks(&days, ymd(10, 21)));
+        assert_eq!(
+            vec![("2026-09", 3), ("2026-10", 2)],
+            bins(&days, Bucket::Month)
+                .iter()
+                .map(|b| (b.label.as_str(), b.entries))
+                .collect::<Vec<_>>()
+        );
+
+        let db = test_db();
+        let today = time::today();
+        let created = time::day_start(today, time::local_offset(time::now())) + 3600;
+        let entry = |id: &str, days: i64, meta: &str, body: &str| {
+            let e = Entry {
+                id: String::from(id),
+                created: created - (days * 86400) as u64,
+                meta: String::from(meta),
+                body: String::from(body),
+                ..Entry::default()
+            };
+            db.insert_entry(&e).unwrap();
+            e
+        };
+        entry("1-a", 0, "{Work/A}", "one two three");
+        entry("1-b", 1, "{Work/B}", "four");
+        let c = entry("1-c", 1, "{Home}", "five six");
+        entry("1-d", 3, "{Work}", "seven");
+
+        let s = db.stats("").unwrap();
+        assert_eq!((4, 7), (s.entries, s.words));
+        assert_eq!((2, 2), (s.current_streak, s.longest_streak));
+        assert_eq!(3, s.days.len());
+        assert_eq!(4, s.streams.len());
+
+        let s = db.stats("{Work}").unwrap();
+        assert_eq!((3, 5), (s.entries, s.words));
+        assert_eq!(
+            vec!["Work/A", "Work/B"],
+            s.streams
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        // Updates and deletions are reflected in the aggregates.
+        db.insert_entry(&Entry {
+            meta: String::from("{Work}"),
+            body: String::from("five"),
+            ..c
+        })
+        .unwrap();
+        let s = db.stats("{Work}").unwrap();
+        assert_eq!((4, 6), (s.entries, s.words));
+        db.delete_entry(&String::from("1-a")).unwrap();
+        let s = db.stats("").unwrap();
+        assert_eq!((3, 3), (s.entries, s.words));
+
+        // Other queries are computed from the matching entries.
+        let s = db.stats("four").unwrap();
+        assert_eq!((1, 1), (s.entries, s.words));
+        assert_eq!(0, db.stats("{Unknown}").unwrap().entries);
+
+        // Heatmaps roll sub-streams up into their parents, counting each entry once.
+        entry("1-e", 1, "{Work/A} {Work/B}", "eight nine");
+        let s = db.stats("{Work}").unwrap();
+        assert_eq!((4, 5), (s.entries, s.words));
+        assert_eq!(
+            s.entries as usize,
+            db.list_entries("{Work}", 0, 10).unwrap().0
+        );
+        let h = db
+            .heatmap("{Work}", today - Duration::days(3), today)
+            .unwrap();
+        assert_eq!(Bucket::Day.label(today), h.to);
+        assert_eq!(vec![1, 0, 3, 0], h.entries);
+        assert_eq!(vec![1, 0, 4, 0], h.words);
+        let h = db.heatmap("{Work/B}", today, today).unwrap();
+        assert_eq!(vec![0], h.entries);
+        assert_eq!(
+            vec![0; 3],
+            db.heatmap("{Unknown}", today - Duration::days(2), today)
+                .unwrap()
+                .entries
+        );
+        assert!(db.heatmap("", today, today - Duration::days(1)).is_err());
+        assert!(db
+            .heatmap("", today - Duration::days(MAX_HEATMAP_DAYS), today)
+            .is_err());
+
+        // Entries in several streams are marked under each of them.
+        assert_eq!(2, db.stats.scan_prefix(b"m\0").count());
+        db.delete_entry(&String::from("1-e")).unwrap();
+        assert_eq!(0, db.stats.scan_prefix(b"m\0").count());
+    }
+}
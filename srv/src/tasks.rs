@@ -0,0 +1,171 @@
+use crate::db::DB;
+use crate::models::Entry;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// A Markdown task item (`- [ ] item` or `- [x] item`) of an entry's body.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Task {
+    /// Position of the task among the tasks of the body.
+    pub index: usize,
+    /// Line of the body the task is on.
+    pub line: usize,
+    pub done: bool,
+    pub text: String,
+}
+
+/// Request to toggle the task `index` of the entry `id`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TaskToggle {
+    pub id: String,
+    pub index: usize,
+}
+
+lazy_static! {
+    static ref TASK_RE: Regex =
+        Regex::new(r"^(?P<pre>\s*[-*+]\s+\[)(?P<mark>[ xX])(?P<post>\]\s+)(?P<text>.*?)\r?$")
+            .unwrap();
+}
+
+/// Extracts the task items of `body`, ignoring the ones in fenced code blocks.
+pub fn parse(body: &str) -> Vec<Task> {
+    let mut tasks = vec![];
+    let mut fenced = false;
+    for (line, l) in body.split('\n').enumerate() {
+        if l.trim_start().starts_with("```") {
+            fenced = !fenced;
+            continue;
+        }
+        if fenced {
+            continue;
+        }
+        if let Some(c) = TASK_RE.captures(l) {
+            tasks.push(Task {
+                index: tasks.len(),
+                line,
+                done: &c["mark"] != " ",
+                text: c["text"].to_string(),
+            });
+        }
+    }
+    tasks
+}
+
+/// Returns `body` with the task `index` checked if it was open, or unchecked otherwise.
+pub fn toggle(body: &str, index: usize) -> Result<String> {
+    let task = match parse(body).into_iter().find(|t| t.index == index) {
+        Some(t) => t,
+        None => return Err(anyhow!("Unknown task: {}", index)),
+    };
+    Ok(body
+        .split('\n')
+        .enumerate()
+        .map(|(i, l)| {
+            if i == task.line {
+                let mark = if task.done { " " } else { "x" };
+                TASK_RE
+                    .replace(l, format!("${{pre}}{}${{post}}${{text}}", mark).as_str())
+                    .to_string()
+                    + if l.ends_with('\r') { "\r" } else { "" }
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Updates the `tasks` index (entry id to its tasks) for the entry `id`.
+pub fn index(tasks: &sled::Tree, id: &str, body: &str) -> Result<()> {
+    let t = parse(body);
+    if t.is_empty() {
+        tasks.remove(id.as_bytes())?;
+    } else {
+        tasks.insert(id.as_bytes(), serialize(&t)?)?;
+    }
+    Ok(())
+}
+
+impl DB {
+    /// Returns the ids of the entries with tasks (only open ones if `open`) from the `tasks`
+    /// index.
+    pub(crate) fn entries_with_tasks(&self, open: bool) -> Result<HashSet<sled::IVec>> {
+        let mut out = HashSet::new();
+        for x in self.tasks.iter() {
+            let (k, v) = x?;
+            let tasks: Vec<Task> = deserialize(&v)?;
+            if open && tasks.iter().all(|t| t.done) {
+                continue;
+            }
+            out.insert(k);
+        }
+        Ok(out)
+    }
+
+    pub fn list_tasks(&self, id: &str) -> Result<Vec<Task>> {
+        match self.tasks.get(id)? {
+            Some(v) => Ok(deserialize(&v)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Toggles the task `index` of the entry `id` and returns the updated entry.
+    pub fn toggle_task(&self, id: &str, index: usize) -> Result<Entry> {
+        let mut entry = match self.get_entry(&id.to_string())? {
+            Some(e) => e,
+            None => return Err(anyhow!("Unknown entry: {}", id)),
+        };
+        entry.body = toggle(&entry.body, index)?;
+        self.insert_entry(&entry)?;
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_db, test_entry};
+
+    #[test]
+    fn test_tasks() {
+        let body = "Plan\r\n- [ ] write\r\n  * [x] review\r\n```\n- [ ] code\n```\n+ [ ] ship it";
+        let t = parse(body);
+        assert_eq!(
+            vec![(0, 1, false), (1, 2, true), (2, 6, false)],
+            t.iter()
+                .map(|t| (t.index, t.line, t.done))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("ship it", t[2].text);
+        assert_eq!("review", t[1].text);
+        assert_eq!(
+            "Plan\r\n- [ ] write\r\n  * [ ] review\r\n```\n- [ ] code\n```\n+ [ ] ship it",
+            toggle(body, 1).unwrap()
+        );
+        assert!(toggle(body, 3).is_err());
+
+        let db = test_db();
+        let entry = |meta: &str, body: &str| test_entry(&db, meta, "", body);
+        let a = entry("{Work}", "- [ ] a\n- [x] b");
+        entry("{Home}", "- [ ] c");
+        entry("{Work}", "- [x] d");
+        entry("{Work}", "nothing");
+
+        assert_eq!(2, db.list_entries("is:open-task", 0, 10).unwrap().0);
+        assert_eq!(1, db.list_entries("is:open-task {Work}", 0, 10).unwrap().0);
+        assert_eq!(2, db.list_entries("is:task {Work}", 0, 10).unwrap().0);
+        assert_eq!(0, db.list_entries("is:unknown", 0, 10).unwrap().0);
+
+        let e = db.toggle_task(&a.id, 0).unwrap();
+        assert_eq!("- [x] a\n- [x] b", e.body);
+        assert!(db.list_tasks(&a.id).unwrap().iter().all(|t| t.done));
+        assert_eq!(0, db.list_entries("is:open-task {Work}", 0, 10).unwrap().0);
+
+        db.delete_entry(&a.id).unwrap();
+        assert!(db.list_tasks(&a.id).unwrap().is_empty());
+    }
+}
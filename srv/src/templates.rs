@@ -0,0 +1,228 @@
+use crate::db::{extract_stream_names, validate_stream_name, DB};
+use crate::fields::parse_value;
+use crate::models::{Entry, EntryCreation, Field};
+use crate::time::{self, Bucket};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+// Templates are stored by name in the `templates` tree. A stream's daily entry is created from the
+// template set in its attributes (`StreamAttributes.template`), or else from the template named
+// after the stream. Daily entries carry a `daily:<date>` field, which is how they are found again.
+
+/// A named skeleton of entry. `title`, `meta` and `body` can contain placeholders, see `render`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Template {
+    pub name: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub meta: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TemplateList {
+    pub total: usize,
+    pub templates: Vec<Template>,
+}
+
+/// Reference to a template by name.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TemplateRef {
+    pub name: String,
+}
+
+/// Request for the daily entry of `stream` on `date` (`YYYY-MM-DD`, `today`, `yesterday` or
+/// `tomorrow`), today if not set.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DailyRequest {
+    pub stream: String,
+    #[serde(default)]
+    pub date: Option<String>,
+}
+
+/// Replaces the `{{date}}`, `{{weekday}}`, `{{week}}`, `{{month}}` and `{{stream}}` placeholders
+/// of `s`. Unknown placeholders are left as is.
+pub fn render(s: &str, stream: &str, date: NaiveDate) -> String {
+    s.replace("{{date}}", &Bucket::Day.label(date))
+        .replace("{{weekday}}", &date.format("%A").to_string())
+        .replace("{{week}}", &Bucket::Week.label(date))
+        .replace("{{month}}", &Bucket::Month.label(date))
+        .replace("{{stream}}", stream)
+}
+
+impl Template {
+    /// Returns the template with its placeholders replaced, see `render`.
+    pub fn render(&self, stream: &str, date: NaiveDate) -> Template {
+        Template {
+            name: self.name.clone(),
+            title: render(&self.title, stream, date),
+            meta: render(&self.meta, stream, date),
+            body: render(&self.body, stream, date),
+        }
+    }
+}
+
+/// Parses the date of a `DailyRequest`, relative dates being resolved against `today`.
+pub fn parse_date(date: Option<&str>, today: NaiveDate) -> Result<NaiveDate> {
+    match date {
+        None => Ok(today),
+        Some(raw) => match parse_value(raw, today) {
+            Field::Date(d) => Ok(NaiveDate::parse_from_str(&d, "%Y-%m-%d")?),
+            _ => Err(anyhow!("Invalid date: {}", raw)),
+        },
+    }
+}
+
+impl DB {
+    pub fn set_template(&self, template: &Template) -> Result<()> {
+        if template.name.trim().is_empty() {
+            return Err(anyhow!("Invalid template name: {}", template.name));
+        }
+        self.templates
+            .insert(template.name.as_bytes(), serialize(template)?)?;
+        Ok(())
+    }
+
+    pub fn get_template(&self, name: &str) -> Result<Option<Template>> {
+        match self.templates.get(name.as_bytes())? {
+            Some(v) => Ok(Some(deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_templates(&self) -> Result<Vec<Template>> {
+        self.templates
+            .iter()
+            .values()
+            .map(|v| Ok(deserialize(&v?)?))
+            .collect()
+    }
+
+    pub fn delete_template(&self, name: &str) -> Result<()> {
+        self.templates.remove(name.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the daily entry of the stream `stream` for `date`, creating the stream and the
+    /// entry from the stream's template if needed.
+    pub fn get_or_create_daily(&self, stream: &str, date: NaiveDate) -> Result<Entry> {
+        validate_stream_name(stream)?;
+        let stream = match self.stream_by_name(stream, true)? {
+            Some(s) => s,
+            None => return Err(anyhow!("Unknown stream: {}", stream)),
+        };
+        let day = Field::Date(Bucket::Day.label(date));
+
+        if let Some(e) = self
+            .entries_between(&format!("{{{}}}", stream.name), date, date)?
+            .into_iter()
+            .find(|e| {
+                e.fields.get("daily") == Some(&day)
+                    && extract_stream_names(&e.meta).contains(&stream.name)
+            })
+        {
+            return Ok(e);
+        }
+
+        let name = stream
+            .attributes()
+            .template
+            .unwrap_or_else(|| stream.name.clone());
+        let template = self
+            .get_template(&name)?
+            .unwrap_or(Template {
+                name,
+                title: String::from("{{date}}"),
+                meta: String::from(""),
+                body: String::from(""),
+            })
+            .render(&stream.name, date);
+
+        // Entries for other days than today are created at noon on that day.
+        let created = if date == time::today() {
+            time::now()
+        } else {
+            time::day_start(date, time::local_offset(time::day_start(date, 0))) + 12 * 3600
+        };
+        self.create_entry(&EntryCreation {
+            meta: format!(
+                "{{{}}} daily:{} {}",
+                stream.name,
+                day.to_token(),
+                template.meta
+            )
+            .trim()
+            .to_string(),
+            title: template.title,
+            body: template.body,
+            created: Some(created),
+            tz_offset: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn test_daily() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        assert_eq!(
+            "Monday 2026-10-19 (2026-W43) {{unknown}}",
+            render(
+                "{{weekday}} {{date}} ({{week}}) {{unknown}}",
+                "Standup",
+                date
+            )
+        );
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2026, 10, 18).unwrap(),
+            parse_date(Some("yesterday"), date).unwrap()
+        );
+        assert!(parse_date(Some("soon"), date).is_err());
+
+        let db = test_db();
+        db.set_template(&Template {
+            name: String::from("Standup"),
+            title: String::from("Standup {{weekday}}"),
+            meta: String::from("{Team}"),
+            body: String::from("## Yesterday\n\n## Today\n\n## Blockers\n"),
+        })
+        .unwrap();
+        assert!(db
+            .set_template(&Template {
+                name: String::from(" "),
+                title: String::from(""),
+                meta: String::from(""),
+                body: String::from(""),
+            })
+            .is_err());
+        assert_eq!(1, db.list_templates().unwrap().len());
+
+        let e = db.get_or_create_daily("Standup", date).unwrap();
+        assert_eq!("Standup Monday", e.title);
+        assert_eq!("{Standup} daily:2026-10-19 {Team}", e.meta);
+        assert_eq!(date, e.date());
+        assert_eq!(e.id, db.get_or_create_daily("Standup", date).unwrap().id);
+
+        // Other streams and days get their own entries, with the default template.
+        let o = db.get_or_create_daily("Journal", date).unwrap();
+        assert_ne!(e.id, o.id);
+        assert_eq!("2026-10-19", o.title);
+        let next = date.succ_opt().unwrap();
+        assert_ne!(e.id, db.get_or_create_daily("Standup", next).unwrap().id);
+        let sub = db.get_or_create_daily("Standup/Team", date).unwrap();
+        assert_ne!(e.id, sub.id);
+        assert_eq!(e.id, db.get_or_create_daily("Standup", date).unwrap().id);
+        assert!(db.get_or_create_daily("Standup/", date).is_err());
+
+        db.delete_template("Standup").unwrap();
+        assert!(db.get_template("Standup").unwrap().is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::models::Entry;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Local, NaiveDate, Offset, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+// Entries record the UTC offset of their author at creation (`Entry.tz_offset`, in seconds east
+// of UTC) so that their day doesn't depend on the time zone of whoever reads them. Days, weeks
+// (starting on Monday, ISO 8601) and months are computed here for queries and timelines.
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Returns the UTC offset of the local time zone at the timestamp `secs`.
+pub fn local_offset(secs: u64) -> i32 {
+    Local
+        .timestamp_opt(secs as i64, 0)
+        .single()
+        .map(|t| t.offset().local_minus_utc())
+        .unwrap_or(0)
+}
+
+/// Returns the date of the timestamp `secs` at the UTC offset `tz_offset`. Timestamps beyond the
+/// range of dates are clamped to `NaiveDate::MAX`.
+pub fn date(secs: u64, tz_offset: i32) -> NaiveDate {
+    let tz = FixedOffset::east_opt(tz_offset).unwrap_or_else(|| Utc.fix());
+    i64::try_from(secs)
+        .ok()
+        .and_then(|s| s.checked_add(tz.local_minus_utc() as i64))
+        .and_then(|s| DateTime::from_timestamp(s, 0))
+        .map(|t| t.date_naive())
+        .unwrap_or(NaiveDate::MAX)
+}
+
+/// Returns the timestamp of the start of the day `d` at the UTC offset `tz_offset`.
+pub fn day_start(d: NaiveDate, tz_offset: i32) -> u64 {
+    (d.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() - tz_offset as i64).max(0) as u64
+}
+
+/// Returns the date of the timestamp `secs` in the local time zone.
+pub fn local_date(secs: u64) -> NaiveDate {
+    date(secs, local_offset(secs))
+}
+
+/// Returns the current date in the local time zone.
+pub fn today() -> NaiveDate {
+    local_date(now())
+}
+
+impl Entry {
+    /// Date of the entry in the time zone of its author.
+    pub fn date(&self) -> NaiveDate {
+        date(
+            self.created,
+            self.tz_offset.unwrap_or_else(|| local_offset(self.created)),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Day,
+    Week,
+    Month,
+}
+
+impl Bucket {
+    /// Returns the first day of the bucket containing `d`.
+    pub fn start(&self, d: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => d,
+            Bucket::Week => d - Duration::days(d.weekday().num_days_from_monday() as i64),
+            Bucket::Month => d.with_day(1).unwrap(),
+        }
+    }
+
+    /// Returns the first day of the bucket following the one containing `d`, or `None` if it is
+    /// past the last representable date.
+    pub fn next(&self, d: NaiveDate) -> Option<NaiveDate> {
+        let start = self.start(d);
+        match self {
+            Bucket::Day => start.checked_add_signed(Duration::days(1)),
+            Bucket::Week => start.checked_add_signed(Duration::days(7)),
+            Bucket::Month => match start.month() {
+                12 => NaiveDate::from_ymd_opt(start.year().checked_add(1)?, 1, 1),
+                m => NaiveDate::from_ymd_opt(start.year(), m + 1, 1),
+            },
+        }
+    }
+
+    /// Returns the first day of the bucket preceding the one containing `d`.
+    pub fn previous(&self, d: NaiveDate) -> NaiveDate {
+        self.start(self.start(d) - Duration::days(1))
+    }
+
+    /// Label of the bucket containing `d`: `2026-10-19`, `2026-W43` or `2026-10`.
+    pub fn label(&self, d: NaiveDate) -> String {
+        match self {
+            Bucket::Day => d.format("%Y-%m-%d").to_string(),
+            Bucket::Week => {
+                let w = d.iso_week();
+                format!("{}-W{:02}", w.year(), w.week())
+            }
+            Bucket::Month => d.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// Parses a bucket as written in queries (a label, `today`, `yesterday`, `this-week`,
+    /// `last-week`, `this-month` or `last-month`) into the bucket and its first day. Relative
+    /// buckets are resolved against `today`.
+    pub fn parse(value: &str, today: NaiveDate) -> Option<(Bucket, NaiveDate)> {
+        match value {
+            "today" => return Some((Bucket::Day, today)),
+            "yesterday" => return Some((Bucket::Day, today - Duration::days(1))),
+            "this-week" => return Some((Bucket::Week, Bucket::Week.start(today))),
+            "last-week" => return Some((Bucket::Week, Bucket::Week.previous(today))),
+            "this-month" => return Some((Bucket::Month, Bucket::Month.start(today))),
+            "last-month" => return Some((Bucket::Month, Bucket::Month.previous(today))),
+            _ => (),
+        }
+        if let Ok(d) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            return Some((Bucket::Day, d));
+        }
+        if let Some((y, w)) = value.split_once("-W") {
+            let y = y.parse::<i32>().ok()?;
+            let w = w.parse::<u32>().ok()?;
+            return NaiveDate::from_isoywd_opt(y, w, chrono::Weekday::Mon)
+                .map(|d| (Bucket::Week, d));
+        }
+        NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d")
+            .ok()
+            .map(|d| (Bucket::Month, d))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        // 2026-10-19 23:30 in New York (UTC-4) is already the 20th in UTC and Paris.
+        let secs = Utc
+            .with_ymd_and_hms(2026, 10, 20, 3, 30, 0)
+            .unwrap()
+            .timestamp() as u64;
+        let ymd = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(ymd(2026, 10, 19), date(secs, -4 * 3600));
+        assert_eq!(ymd(2026, 10, 20), date(secs, 2 * 3600));
+        assert_eq!(NaiveDate::MAX, date(u64::MAX, 0));
+        let e = Entry {
+            created: secs,
+            tz_offset: Some(-4 * 3600),
+            ..Entry::default()
+        };
+        assert_eq!(ymd(2026, 10, 19), e.date());
+        assert_eq!(
+            secs - 3600 * 23 - 1800,
+            day_start(ymd(2026, 10, 19), -4 * 3600)
+        );
+
+        let d = ymd(2026, 12, 31);
+        assert_eq!(ymd(2026, 12, 28), Bucket::Week.start(d));
+        assert_eq!(Some(ymd(2027, 1, 4)), Bucket::Week.next(d));
+        assert_eq!(Some(ymd(2027, 1, 1)), Bucket::Month.next(d));
+        assert_eq!(None, Bucket::Month.next(NaiveDate::MAX));
+        assert_eq!(None, Bucket::Day.next(NaiveDate::MAX));
+        assert!(Bucket::parse(&Bucket::Month.label(NaiveDate::MAX), d).is_some());
+        assert_eq!(ymd(2026, 11, 1), Bucket::Month.previous(d));
+        assert_eq!("2026-W53", Bucket::Week.label(d));
+        assert_eq!("2026-12", Bucket::Month.label(d));
+
+        let today = ymd(2026, 10, 19);
+        assert_eq!(
+            Some((Bucket::Week, ymd(2026, 10, 12))),
+            Bucket::parse("last-week", today)
+        );
+        assert_eq!(
+            Some((Bucket::Week, ymd(2026, 12, 28))),
+            Bucket::parse("2026-W53", today)
+        );
+        assert_eq!(
+            Some((Bucket::Month, ymd(2026, 9, 1))),
+            Bucket::parse("2026-09", today)
+        );
+        assert_eq!(None, Bucket::parse("soon", today));
+    }
+}